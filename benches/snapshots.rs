@@ -0,0 +1,153 @@
+//! Compares how much each snapshot format keeps, and how long it takes to save
+//! and restore, on a made-up arena shaped like ours: four walls, a ball and a
+//! couple of players running around.  The second run adds a lot more static
+//! geometry, which the delta format should barely notice.
+//!
+//! ```text
+//! cargo bench --bench snapshots
+//! ```
+
+use std::time::{Duration, Instant};
+
+use bevy_ggrs_rapier_example::snapshots::{PhysicsSnapshots, SnapshotFormat};
+use bevy_rapier2d::plugin::RapierContext;
+use bevy_rapier2d::rapier::math::Vector;
+use bevy_rapier2d::rapier::prelude::{ColliderBuilder, RigidBodyBuilder, RigidBodyHandle};
+use ggrs::Frame;
+
+const FRAMES: Frame = 600;
+
+/// How far back every rollback goes, and how often we do one
+const ROLLBACK_DEPTH: Frame = 3;
+const ROLLBACK_INTERVAL: Frame = 10;
+
+fn main() {
+    for (name, static_blocks) in [("our arena", 0), ("with 200 more static blocks", 200)] {
+        println!("{} ({} frames)", name, FRAMES);
+        bench(static_blocks);
+    }
+}
+
+fn bench(static_blocks: usize) {
+    let (mut context, players) = arena(static_blocks);
+    let formats = [SnapshotFormat::Full, SnapshotFormat::Delta];
+    let mut stores = formats.map(PhysicsSnapshots::new);
+    let mut stored_bytes = [0; 2];
+    let mut save_time = [Duration::ZERO; 2];
+    let mut restore_time = [Duration::ZERO; 2];
+    let mut restored = RapierContext::default();
+
+    for frame in 0..FRAMES {
+        step(&mut context, &players, frame);
+
+        let mut checksums = [0; 2];
+        for (i, store) in stores.iter_mut().enumerate() {
+            let started = Instant::now();
+            checksums[i] = store.save(frame, &context).unwrap();
+            save_time[i] += started.elapsed();
+            stored_bytes[i] += store.stored_bytes(frame);
+
+            if frame % ROLLBACK_INTERVAL == 0 && frame >= ROLLBACK_DEPTH {
+                let started = Instant::now();
+                store
+                    .restore(frame - ROLLBACK_DEPTH, &mut restored)
+                    .unwrap();
+                restore_time[i] += started.elapsed();
+            }
+        }
+        assert_eq!(
+            checksums[0], checksums[1],
+            "Formats disagree on frame {}",
+            frame
+        );
+    }
+
+    let frames = FRAMES as u32;
+    let restores = (FRAMES / ROLLBACK_INTERVAL) as u32;
+    for (i, format) in formats.iter().enumerate() {
+        println!(
+            "  {:<6} {:>8} bytes/frame  save {:>10?}/frame  restore {:>10?}/rollback",
+            format!("{:?}", format),
+            stored_bytes[i] / FRAMES as usize,
+            save_time[i] / frames,
+            restore_time[i] / restores,
+        );
+    }
+}
+
+/// Walls around a 320 by 320 arena, plus `static_blocks` more in a grid, with a
+/// ball and two players inside.  Returns the players.
+fn arena(static_blocks: usize) -> (RapierContext, Vec<RigidBodyHandle>) {
+    let mut context = RapierContext::default();
+    let c = &mut context;
+
+    let mut fixed = |x: f32, y: f32, half_width: f32, half_height: f32| {
+        let body = c
+            .bodies
+            .insert(RigidBodyBuilder::fixed().translation(Vector::new(x, y)));
+        c.colliders.insert_with_parent(
+            ColliderBuilder::cuboid(half_width, half_height),
+            body,
+            &mut c.bodies,
+        );
+    };
+    fixed(0., -160., 170., 10.);
+    fixed(0., 160., 170., 10.);
+    fixed(-160., 0., 10., 170.);
+    fixed(160., 0., 10., 170.);
+    for i in 0..static_blocks {
+        let (row, column) = (i / 20, i % 20);
+        fixed(
+            -140. + column as f32 * 14.,
+            -100. + row as f32 * 14.,
+            4.,
+            4.,
+        );
+    }
+
+    let mut dynamic = |x: f32, collider: ColliderBuilder| {
+        let body = c.bodies.insert(
+            RigidBodyBuilder::dynamic()
+                .translation(Vector::new(x, 120.))
+                .ccd_enabled(true),
+        );
+        c.colliders
+            .insert_with_parent(collider, body, &mut c.bodies);
+        body
+    };
+    dynamic(0., ColliderBuilder::ball(4.).restitution(2.));
+    let players = vec![
+        dynamic(-60., ColliderBuilder::cuboid(8., 8.)),
+        dynamic(60., ColliderBuilder::cuboid(8., 8.)),
+    ];
+
+    (context, players)
+}
+
+/// Players run back and forth, changing direction every second
+fn step(context: &mut RapierContext, players: &[RigidBodyHandle], frame: Frame) {
+    let direction = if (frame / 60) % 2 == 0 { 1. } else { -1. };
+    for (i, player) in players.iter().enumerate() {
+        if let Some(body) = context.bodies.get_mut(*player) {
+            let speed = if i % 2 == 0 { 100. } else { -100. };
+            body.set_linvel(Vector::new(direction * speed, body.linvel().y), true);
+        }
+    }
+
+    let c = context;
+    c.pipeline.step(
+        &Vector::new(0., -98.1),
+        &c.integration_parameters,
+        &mut c.islands,
+        &mut c.broad_phase,
+        &mut c.narrow_phase,
+        &mut c.bodies,
+        &mut c.colliders,
+        &mut c.impulse_joints,
+        &mut c.multibody_joints,
+        &mut c.ccd_solver,
+        Some(&mut c.query_pipeline),
+        &(),
+        &(),
+    );
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use crate::prelude::*;
+
+/// Frames before each ability can be used again
+pub const JUMP_COOLDOWN: u32 = FPS as u32 / 2;
+pub const DASH_COOLDOWN: u32 = FPS as u32;
+pub const KICK_COOLDOWN: u32 = FPS as u32 / 3;
+
+/// How many frames `apply_inputs` leaves a player's velocity alone after an
+/// impulse, so it gets to play out instead of being stopped dead
+pub const MOMENTUM_FRAMES: u32 = FPS as u32 / 2;
+
+// A player weighs about 256, the ball about 50
+pub const JUMP_IMPULSE: f32 = 40_000.;
+pub const DASH_IMPULSE: f32 = 60_000.;
+pub const KICK_IMPULSE: f32 = 20_000.;
+
+/// How close something has to be to a player to be kicked
+pub const KICK_RANGE: f32 = 24.;
+
+/// Frames left until each of a player's abilities can be used again.  This is
+/// game state like any other, so it is rolled back and checksummed; otherwise a
+/// resimulated frame could find an ability ready that was not the first time.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Hash, Component, Reflect)]
+#[reflect(Component, Hash, PartialEq)]
+pub struct AbilityCooldowns {
+    pub jump: u32,
+    pub dash: u32,
+    pub kick: u32,
+    /// See [`MOMENTUM_FRAMES`]
+    pub momentum: u32,
+}
+
+impl AbilityCooldowns {
+    fn tick(&mut self) {
+        self.jump = self.jump.saturating_sub(1);
+        self.dash = self.dash.saturating_sub(1);
+        self.kick = self.kick.saturating_sub(1);
+        self.momentum = self.momentum.saturating_sub(1);
+    }
+}
+
+/// Jump, dash and kick, through Rapier's [`ExternalImpulse`].  Runs before
+/// `apply_inputs`, so that knows which players are still carried by an impulse.
+pub fn apply_abilities(
+    inputs: Res<PlayerInputs<ExampleGgrsConfig>>,
+    physics_enabled: Res<PhysicsEnabled>,
+    mut players: Query<(Entity, &Player, &mut AbilityCooldowns)>,
+    mut bodies: Query<(Entity, &Transform, &mut ExternalImpulse), With<Rollback>>,
+) {
+    // Same as `apply_inputs`, nothing happens until physics are live
+    if !physics_enabled.0 {
+        return;
+    }
+
+    // Sorted by handle, so impulses on the same body are added up in the same
+    // order on every peer
+    let mut players = players.iter_mut().collect::<Vec<_>>();
+    players.sort_by_key(|(_, p, _)| p.handle);
+
+    let mut impulses: BTreeMap<Entity, Vec2> = BTreeMap::new();
+    for &mut (entity, player, ref mut cooldowns) in players.iter_mut() {
+        cooldowns.tick();
+
+        let (game_input, input_status) = inputs[player.handle];
+        if matches!(input_status, InputStatus::Disconnected) {
+            continue;
+        }
+        let pressed = |bit| game_input.input & bit != 0;
+
+        if pressed(INPUT_JUMP) && cooldowns.jump == 0 {
+            *impulses.entry(entity).or_default() += Vec2::Y * JUMP_IMPULSE;
+            cooldowns.jump = JUMP_COOLDOWN;
+        }
+
+        // Dashing needs a direction to dash in.  Only the direction, so a
+        // diagonal dash goes no further than a straight one.
+        let direction = game_input.direction().normalize_or_zero();
+        if pressed(INPUT_DASH) && cooldowns.dash == 0 && direction != Vec2::ZERO {
+            *impulses.entry(entity).or_default() += direction * DASH_IMPULSE;
+            cooldowns.dash = DASH_COOLDOWN;
+        }
+
+        if pressed(INPUT_KICK) && cooldowns.kick == 0 {
+            let Ok((_, kicker, _)) = bodies.get(entity) else {
+                continue;
+            };
+            let kicker = kicker.translation.truncate();
+
+            // Push everything close by directly away from us
+            for (target, transform, _) in bodies.iter() {
+                let offset = transform.translation.truncate() - kicker;
+                if target != entity && offset.length_squared() <= KICK_RANGE * KICK_RANGE {
+                    *impulses.entry(target).or_default() +=
+                        offset.normalize_or_zero() * KICK_IMPULSE;
+                }
+            }
+            cooldowns.kick = KICK_COOLDOWN;
+        }
+    }
+
+    // Including players that were kicked
+    for &mut (entity, _, ref mut cooldowns) in players.iter_mut() {
+        if impulses.contains_key(&entity) {
+            cooldowns.momentum = MOMENTUM_FRAMES;
+        }
+    }
+
+    for (entity, _, mut external) in bodies.iter_mut() {
+        match impulses.get(&entity) {
+            // Always a change, even when it is the same impulse as last frame,
+            // which Rapier needs to see to apply it again
+            Some(impulse) => external.impulse = *impulse,
+            // Rapier only applies impulses that changed, so clear last frame's
+            // without it noticing.  Resimulated frames set every impulse from
+            // scratch, so there is nothing here to roll back.
+            None if external.impulse != Vec2::ZERO => {
+                external.bypass_change_detection().impulse = Vec2::ZERO
+            }
+            None => (),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::prelude::*;
+
+/// How this instance gets its GGRS session
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum RunMode {
+    /// Find another player through the matchbox and play over WebRTC
+    #[default]
+    Matchbox,
+    /// Run a local SyncTest session, rolling back and resimulating every frame
+    /// to check that our simulation is deterministic
+    SyncTest,
+    /// Play directly over UDP with the `--udp-players`, no matchbox needed.
+    /// Not available on the web.
+    Udp,
+    /// Watch a match without playing.  With `--udp-host`, directly over UDP,
+    /// where the host must list us in its `--udp-spectators` (not available
+    /// on the web).  Otherwise as one of the `--spectators` in the matchbox
+    /// room.
+    Spectate,
+    /// Play back a `--replay` through a local session, checking every frame
+    /// ends the way it did when it was recorded.  Not available on the web.
+    Replay,
+}
+
+/// Runtime settings, from the command line or environment variables.  On the
+/// web, the same settings are read from the page's query parameters instead,
+/// e.g., `?room=my-room&matchbox=ws://localhost:3536`.
+#[derive(Parser, Resource, Clone, Debug)]
+#[command(about)]
+pub struct Args {
+    /// How to start the GGRS session
+    #[arg(long, value_enum, env = "RUN_MODE", default_value_t = RunMode::default())]
+    pub mode: RunMode,
+
+    /// How many players are in a match, from 2 to 4
+    #[arg(long, env = "PLAYERS", default_value_t = 2, value_parser = parse_players)]
+    pub players: usize,
+
+    /// How many spectators the matchbox room waits for besides the players.
+    /// Like `--players`, everyone in the room must agree on this, and the
+    /// match only starts once all of them are there.
+    #[arg(long, env = "SPECTATORS", default_value_t = 0)]
+    pub spectators: usize,
+
+    /// The matchbox signaling server to find other players with
+    #[arg(long, env = "MATCHBOX_SERVER", default_value = MATCHBOX_SERVER)]
+    pub matchbox: String,
+
+    /// The matchbox room to join.  Only players in the same room are matched.
+    #[arg(long, env = "MATCHBOX_ROOM", default_value = MATCHBOX_ROOM)]
+    pub room: String,
+
+    /// How many frames a SyncTest session rolls back and resimulates each
+    /// frame.  Must be less than the prediction window.
+    #[arg(long, env = "CHECK_DISTANCE", default_value_t = 2, value_parser = parse_check_distance)]
+    pub check_distance: usize,
+
+    /// The local port to bind in `udp` mode
+    #[arg(long, env = "UDP_PORT", default_value_t = 7000)]
+    pub udp_port: u16,
+
+    /// Every player in `udp` mode, in handle order, separated by commas.
+    /// Use `local` for ourselves and an `ip:port` for everyone else, e.g.,
+    /// `local,127.0.0.1:7001`
+    #[arg(long, env = "UDP_PLAYERS", value_delimiter = ',')]
+    pub udp_players: Vec<String>,
+
+    /// Addresses of spectators allowed to watch our `udp` match, separated by
+    /// commas
+    #[arg(long, env = "UDP_SPECTATORS", value_delimiter = ',')]
+    pub udp_spectators: Vec<String>,
+
+    /// The address of the player we watch in `spectate` mode
+    #[arg(long, env = "UDP_HOST")]
+    pub udp_host: Option<String>,
+
+    /// How far a spectator may fall behind the host before catching up
+    #[arg(long, env = "MAX_FRAMES_BEHIND", default_value_t = 10)]
+    pub max_frames_behind: usize,
+
+    /// How many frames a spectator advances per frame while catching up.  Must
+    /// be less than `--max-frames-behind`.
+    #[arg(long, env = "CATCHUP_SPEED", default_value_t = 2)]
+    pub catchup_speed: usize,
+
+    /// Write rollback metrics to this CSV file when we exit
+    #[arg(long, env = "METRICS_CSV")]
+    pub metrics_csv: Option<PathBuf>,
+
+    /// How physics snapshots are stored.  Peers using different formats still
+    /// agree on checksums.
+    #[arg(long, env = "SNAPSHOT_FORMAT", value_enum, default_value_t)]
+    pub snapshot_format: SnapshotFormat,
+
+    /// How much checksum logging and rehashing to do every frame, to help
+    /// track down desyncs
+    #[arg(long, env = "DESYNC_DIAGNOSTICS", value_enum, default_value_t)]
+    pub desync_diagnostics: DesyncDiagnostics,
+
+    /// How log lines are written.  `json` gives every line the frame, whether
+    /// it is being resimulated, and our peer id as fields.
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Log to a file of our own in this directory, instead of stdout.  Files
+    /// are named after our local player handles, peer id and process id.
+    #[arg(long, env = "LOG_DIR")]
+    pub log_dir: Option<PathBuf>,
+
+    /// Record every match's inputs to a replay file in this directory,
+    /// written when the match ends
+    #[arg(long, env = "REPLAY_DIR")]
+    pub replay_dir: Option<PathBuf>,
+
+    /// The replay file to play back in `replay` mode
+    #[arg(long, env = "REPLAY")]
+    pub replay: Option<PathBuf>,
+
+    /// How fast to play back a replay
+    #[arg(long, env = "REPLAY_SPEED", value_enum, default_value_t)]
+    pub replay_speed: ReplaySpeed,
+
+    /// Seed for the [`RollbackRng`], which random input and anything else
+    /// random in the simulation draws from.  Every peer must use the same one,
+    /// and it is recorded in replays.
+    #[arg(long, env = "SEED", default_value_t = 0)]
+    pub seed: u64,
+
+    /// Drop an extra ball in every second, which goes away again after two.
+    /// Every peer must agree on this.
+    #[arg(long, env = "EXTRA_BALLS")]
+    pub extra_balls: bool,
+
+    /// Read key and gamepad bindings from this RON file, and save them back to
+    /// it after rebinding them in game (F1).  Created if it does not exist.
+    #[arg(long, env = "INPUT_MAP")]
+    pub input_map: Option<PathBuf>,
+
+    /// Run without a window or renderer, e.g., for determinism checks on
+    /// machines without a GPU.  Pair with `--mode sync-test` for a fully local
+    /// check.
+    #[arg(long, env = "HEADLESS")]
+    pub headless: bool,
+}
+
+impl Args {
+    /// Reads our settings from wherever this platform keeps them
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_env() -> Self {
+        Self::parse()
+    }
+
+    /// Browsers have no command line, so turn the page's query parameters into
+    /// one.  `?room=abc&headless` becomes `--room=abc --headless`.
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_env() -> Self {
+        let search = web_sys::window()
+            .and_then(|w| w.location().search().ok())
+            .unwrap_or_default();
+        let params = web_sys::UrlSearchParams::new_with_str(&search)
+            .expect("Could not parse query parameters");
+
+        let command = <Self as clap::CommandFactory>::command();
+        let mut args = vec![command.get_name().to_string()];
+        for arg in command.get_arguments() {
+            let Some(long) = arg.get_long() else {
+                continue;
+            };
+            match params.get(long) {
+                Some(value) if value.is_empty() => args.push(format!("--{}", long)),
+                Some(value) => args.push(format!("--{}={}", long, value)),
+                None => (),
+            }
+        }
+
+        Self::parse_from(args)
+    }
+
+    /// The full matchbox address, including our room and how many peers the
+    /// room should wait for before matching everyone up
+    pub fn matchbox_url(&self) -> String {
+        format!(
+            "{}/{}?next={}",
+            self.matchbox.trim_end_matches('/'),
+            self.room,
+            self.players + self.spectators
+        )
+    }
+}
+
+fn parse_players(s: &str) -> Result<usize, String> {
+    let players = s.parse::<usize>().map_err(|e| e.to_string())?;
+    if (2..=MAX_PLAYERS).contains(&players) {
+        Ok(players)
+    } else {
+        Err(format!("must be between 2 and {}", MAX_PLAYERS))
+    }
+}
+
+fn parse_check_distance(s: &str) -> Result<usize, String> {
+    let check_distance = s.parse::<usize>().map_err(|e| e.to_string())?;
+    // GGRS refuses to start a SyncTest session otherwise
+    if check_distance < MAX_PREDICTION {
+        Ok(check_distance)
+    } else {
+        Err(format!(
+            "must be less than the prediction window of {}",
+            MAX_PREDICTION
+        ))
+    }
+}
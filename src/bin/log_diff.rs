@@ -0,0 +1,398 @@
+//! Compares the frame logs of two peers, e.g., `cargo run > log1.log` on one
+//! machine and `cargo run > log2.log` on the other, and reports the first frame
+//! where they disagree.  Logs written with `--log-format json` work too.
+//!
+//! `cargo run --bin log_diff -- log1.log log2.log`
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::Parser;
+use serde_json::Value;
+
+/// Finds the first frame two peers' logs disagree on
+#[derive(Parser, Debug)]
+#[command(about)]
+struct Args {
+    /// The log of one peer
+    left: PathBuf,
+    /// The log of the other peer
+    right: PathBuf,
+}
+
+/// What we care about from one simulation of a frame
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct FrameLog {
+    /// From "Context hash after save", only logged with
+    /// `--desync-diagnostics checksums` or higher
+    hash: Option<String>,
+    /// `(handle, input)`, from the "input ..." lines.  Whether an input was
+    /// confirmed or predicted is left out, as that differs between peers even
+    /// when the input itself does not.
+    inputs: Vec<(String, String)>,
+    /// Every line, to show when a frame differs
+    lines: Vec<String>,
+}
+
+/// Every frame in a log, as it was last simulated
+#[derive(Debug, Default)]
+struct PeerLog {
+    frames: BTreeMap<i32, FrameLog>,
+    /// The newest frame this peer knew to be confirmed
+    confirmed: Option<i32>,
+    /// How many times a frame was simulated again
+    resimulations: usize,
+}
+
+impl PeerLog {
+    fn parse(log: &str) -> Result<Self, String> {
+        let mut peer = PeerLog::default();
+        let mut current: Option<(i32, FrameLog)> = None;
+
+        for (number, line) in log.lines().enumerate() {
+            let line = message(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            let line = line.trim();
+            if let Some(frame) = marker(line, "---- start frame ", " ----") {
+                // A start without an end means the log was cut off mid-frame
+                current = Some((frame, FrameLog::default()));
+            } else if let Some(frame) = marker(line, "----- end frame ", " -----") {
+                if let Some((start, log)) = current.take() {
+                    if start == frame {
+                        // Rollbacks simulate the same frame again.  The last
+                        // time is the one with the most confirmed inputs, and
+                        // the one that counts.
+                        if peer.frames.insert(frame, log).is_some() {
+                            peer.resimulations += 1;
+                        }
+                    }
+                }
+            } else if let Some(frame) = line
+                .strip_prefix("confirmed frame: ")
+                .and_then(|f| f.parse().ok())
+            {
+                peer.confirmed = peer.confirmed.max(Some(frame));
+            } else if let Some((_, log)) = current.as_mut() {
+                if let Some(hash) = line.strip_prefix("Context hash after save: ") {
+                    log.hash = Some(hash.to_owned());
+                } else if let Some(input) = parse_input(line) {
+                    log.inputs.push(input);
+                }
+                log.lines.push(line.to_owned());
+            }
+        }
+
+        for log in peer.frames.values_mut() {
+            log.inputs.sort();
+        }
+        Ok(peer)
+    }
+}
+
+/// The message of a log line, whether it was written as text or as JSON
+fn message(line: &str) -> Result<Cow<'_, str>, String> {
+    if !line.trim_start().starts_with('{') {
+        return Ok(Cow::Borrowed(line));
+    }
+
+    let json = serde_json::from_str::<Value>(line)
+        .map_err(|e| format!("not a `--log-format json` line ({})", e))?;
+    match json.get("message") {
+        Some(Value::String(message)) => Ok(Cow::Owned(message.clone())),
+        _ => Err("JSON log line without a message".to_owned()),
+    }
+}
+
+/// The frame number between `prefix` and `suffix`
+fn marker(line: &str, prefix: &str, suffix: &str) -> Option<i32> {
+    line.strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+/// `input Confirmed from 1: 4 (0, -127)` becomes `("1", "4 (0, -127)")`
+fn parse_input(line: &str) -> Option<(String, String)> {
+    let (_, rest) = line.strip_prefix("input ")?.split_once(" from ")?;
+    let (handle, input) = rest.split_once(": ")?;
+    Some((handle.to_owned(), input.to_owned()))
+}
+
+/// How two logs compare
+#[derive(Debug, PartialEq, Eq)]
+enum Comparison {
+    /// Every frame both peers confirmed matches
+    Agree { first: i32, last: i32 },
+    /// The first confirmed frame that does not
+    Differ {
+        frame: i32,
+        hashes: bool,
+        inputs: bool,
+    },
+    /// The first confirmed frame only one of the logs has
+    Missing { frame: i32, from_left: bool },
+    /// There is no confirmed frame both logs have
+    NothingToCompare,
+}
+
+fn compare(left: &PeerLog, right: &PeerLog) -> Comparison {
+    // Frames past the confirmed frame may still be predictions, and can differ
+    // without anything being wrong
+    let confirmed = match (left.confirmed, right.confirmed) {
+        (Some(l), Some(r)) => l.min(r),
+        _ => return Comparison::NothingToCompare,
+    };
+
+    // A frame one peer never logged is as much a difference as any other
+    let frames = left
+        .frames
+        .range(..=confirmed)
+        .chain(right.frames.range(..=confirmed))
+        .map(|(frame, _)| *frame)
+        .collect::<BTreeSet<_>>();
+
+    let mut first = None;
+    let mut last = None;
+    for frame in frames {
+        let (l, r) = match (left.frames.get(&frame), right.frames.get(&frame)) {
+            (Some(l), Some(r)) => (l, r),
+            (l, _) => {
+                return Comparison::Missing {
+                    frame,
+                    from_left: l.is_none(),
+                }
+            }
+        };
+
+        let hashes = l.hash != r.hash;
+        let inputs = l.inputs != r.inputs;
+        if hashes || inputs {
+            return Comparison::Differ {
+                frame,
+                hashes,
+                inputs,
+            };
+        }
+
+        first = first.or(Some(frame));
+        last = Some(frame);
+    }
+
+    match (first, last) {
+        (Some(first), Some(last)) => Comparison::Agree { first, last },
+        _ => Comparison::NothingToCompare,
+    }
+}
+
+fn read(path: &Path) -> Result<PeerLog, String> {
+    let log =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+    PeerLog::parse(&log).map_err(|e| format!("Could not parse {:?}, {}", path, e))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let (left, right) = match (read(&args.left), read(&args.right)) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    for (path, log) in [(&args.left, &left), (&args.right, &right)] {
+        println!(
+            "{:?}: {} frames, {} resimulated, confirmed up to {:?}",
+            path,
+            log.frames.len(),
+            log.resimulations,
+            log.confirmed
+        );
+        if log.frames.values().all(|f| f.hash.is_none()) {
+            println!("  no context hashes, so only comparing inputs");
+            println!("  (context hashes are logged with `--desync-diagnostics checksums`)");
+        }
+    }
+
+    match compare(&left, &right) {
+        Comparison::Agree { first, last } => {
+            println!(
+                "Logs agree on every confirmed frame from {} to {}",
+                first, last
+            );
+            ExitCode::SUCCESS
+        }
+        Comparison::NothingToCompare => {
+            println!("No confirmed frames in common to compare");
+            ExitCode::SUCCESS
+        }
+        Comparison::Differ {
+            frame,
+            hashes,
+            inputs,
+        } => {
+            let what = match (hashes, inputs) {
+                (true, true) => "context hashes and inputs",
+                (true, false) => "context hashes",
+                _ => "inputs",
+            };
+            println!("First difference on frame {}: {} differ", frame, what);
+            for (path, log) in [(&args.left, &left), (&args.right, &right)] {
+                println!("\n{:?}:", path);
+                for line in log.frames[&frame].lines.iter() {
+                    println!("  {}", line);
+                }
+            }
+            ExitCode::FAILURE
+        }
+        Comparison::Missing { frame, from_left } => {
+            let (missing, has, log) = if from_left {
+                (&args.left, &args.right, &right)
+            } else {
+                (&args.right, &args.left, &left)
+            };
+            println!(
+                "First difference on frame {}: missing from {:?}",
+                frame, missing
+            );
+            println!("\n{:?}:", has);
+            for line in log.frames[&frame].lines.iter() {
+                println!("  {}", line);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(n: i32, hash: &str, input: Option<&str>) -> String {
+        let input = input
+            .map(|i| format!("input Predicted from 0: {}\n", i))
+            .unwrap_or_default();
+        format!(
+            "---- start frame {n} ----\n{input}Context hash after save: {hash}\n----- end frame {n} -----\n"
+        )
+    }
+
+    #[test]
+    fn rollbacks_replace_predicted_frames() {
+        // The left peer predicted frame 1 wrong, then rolled back and fixed it
+        let left = [
+            frame(0, "a", None),
+            frame(1, "x", None),
+            "rollback on 1 to 1\n".to_owned(),
+            frame(1, "b", Some("4")),
+            "confirmed frame: 1\n".to_owned(),
+        ]
+        .concat();
+        let right = [
+            frame(0, "a", None),
+            frame(1, "b", Some("4")),
+            "confirmed frame: 1\n".to_owned(),
+        ]
+        .concat();
+
+        let left = PeerLog::parse(&left).unwrap();
+        assert_eq!(left.resimulations, 1);
+        assert_eq!(
+            compare(&left, &PeerLog::parse(&right).unwrap()),
+            Comparison::Agree { first: 0, last: 1 }
+        );
+    }
+
+    #[test]
+    fn finds_the_first_difference() {
+        let left = [
+            frame(0, "a", None),
+            frame(1, "b", None),
+            frame(2, "c", None),
+            "confirmed frame: 2\n".to_owned(),
+        ]
+        .concat();
+        let right = [
+            frame(0, "a", None),
+            frame(1, "b", Some("1")),
+            frame(2, "d", None),
+            "confirmed frame: 2\n".to_owned(),
+        ]
+        .concat();
+
+        assert_eq!(
+            compare(
+                &PeerLog::parse(&left).unwrap(),
+                &PeerLog::parse(&right).unwrap()
+            ),
+            Comparison::Differ {
+                frame: 1,
+                hashes: false,
+                inputs: true
+            }
+        );
+    }
+
+    #[test]
+    fn missing_frames_are_a_difference() {
+        let left = [
+            frame(0, "a", None),
+            frame(1, "b", None),
+            frame(2, "c", None),
+            "confirmed frame: 2\n".to_owned(),
+        ]
+        .concat();
+        let right = [
+            frame(0, "a", None),
+            frame(2, "c", None),
+            "confirmed frame: 2\n".to_owned(),
+        ]
+        .concat();
+
+        assert_eq!(
+            compare(
+                &PeerLog::parse(&left).unwrap(),
+                &PeerLog::parse(&right).unwrap()
+            ),
+            Comparison::Missing {
+                frame: 1,
+                from_left: false
+            }
+        );
+    }
+
+    #[test]
+    fn reads_json_logs() {
+        // What `--log-format json` writes for the same frame
+        let json = |message: &str| {
+            format!(
+                "{}\n",
+                serde_json::json!({"level": "INFO", "frame": 1, "message": message})
+            )
+        };
+        let left = [
+            json("---- start frame 1 ----"),
+            json("input Confirmed from 0: 4 (0, 0)"),
+            json("Context hash after save: b"),
+            json("----- end frame 1 -----"),
+            json("confirmed frame: 1"),
+        ]
+        .concat();
+        let right = [
+            frame(1, "b", Some("4 (0, 0)")),
+            "confirmed frame: 1\n".to_owned(),
+        ]
+        .concat();
+
+        assert_eq!(
+            compare(
+                &PeerLog::parse(&left).unwrap(),
+                &PeerLog::parse(&right).unwrap()
+            ),
+            Comparison::Agree { first: 1, last: 1 }
+        );
+        assert!(PeerLog::parse("{\"not\": \"a log line\"}").is_err());
+        assert!(PeerLog::parse("{ not json").is_err());
+    }
+}
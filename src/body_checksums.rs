@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use bevy::utils::FixedState;
+use bevy_ggrs::RollbackFrameCount;
+
+use crate::prelude::*;
+
+/// Checksums of one rollback entity at the end of a frame.  When the context
+/// checksum says physics diverged, comparing these between peers says which
+/// body it was, and what about it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BodyChecksum {
+    /// The entity's [`Name`], or the entity itself if it has none
+    pub name: String,
+    pub transform: u64,
+    pub velocity: Option<u64>,
+    pub sleeping: Option<u64>,
+    /// The Rapier rigid body, serialized
+    pub rigid_body: Option<u64>,
+    /// The Rapier collider, serialized
+    pub collider: Option<u64>,
+}
+
+/// Per-body checksums for the last [`ROLLBACK_HISTORY_LEN`] frames, sorted by
+/// name.  Resimulated frames overwrite their predicted entries.  Like
+/// [`RollbackHistory`], this is left outside of the rollback system.
+#[derive(Default, Debug, Resource)]
+pub struct BodyChecksums(pub BTreeMap<Frame, Vec<BodyChecksum>>);
+
+impl BodyChecksums {
+    /// One line per body, grouped by frame, so reports from both peers line up
+    /// when diffed
+    pub fn to_report(&self) -> String {
+        let mut report = String::new();
+        for (frame, bodies) in self.0.iter() {
+            let _ = writeln!(report, "frame {}", frame);
+            for body in bodies {
+                let _ = writeln!(report, "  {}", body);
+            }
+        }
+        report
+    }
+}
+
+impl std::fmt::Display for BodyChecksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: transform {} velocity {:?} sleeping {:?} rigid body {:?} collider {:?}",
+            self.name, self.transform, self.velocity, self.sleeping, self.rigid_body, self.collider
+        )
+    }
+}
+
+/// Floats are not [`Hash`], so we hash their bits instead.  `-0.0` and `0.0`
+/// hash differently, which is what we want when hunting for a desync.
+fn hash_floats(floats: &[f32]) -> u64 {
+    let mut hasher = FixedState.build_hasher();
+    for f in floats {
+        f.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn hash_serialized<T: serde::Serialize>(value: &T) -> Option<u64> {
+    bincode::serialize(value).ok().map(|bytes| checksum(&bytes))
+}
+
+pub fn body_checksums_enabled(args: Res<Args>) -> bool {
+    args.desync_diagnostics >= DesyncDiagnostics::Bodies
+}
+
+/// Runs after the context is saved, so it sees the same state the context
+/// checksum does.  Only runs with `--desync-diagnostics bodies` or higher (see
+/// [`body_checksums_enabled`]), as it serializes every body and collider every
+/// frame.
+#[allow(clippy::type_complexity)]
+pub fn record_body_checksums(
+    current_frame: Res<RollbackFrameCount>,
+    rapier: Res<RapierContext>,
+    mut checksums: ResMut<BodyChecksums>,
+    bodies: Query<
+        (
+            Entity,
+            Option<&Name>,
+            &Transform,
+            Option<&Velocity>,
+            Option<&Sleeping>,
+            Option<&RapierRigidBodyHandle>,
+            Option<&RapierColliderHandle>,
+        ),
+        With<Rollback>,
+    >,
+) {
+    let current_frame: i32 = (*current_frame).into();
+
+    let mut frame = bodies
+        .iter()
+        .map(
+            |(e, name, transform, velocity, sleeping, rigid_body, collider)| BodyChecksum {
+                name: name.map_or_else(|| format!("{:?}", e), |n| n.as_str().to_owned()),
+                transform: hash_floats(
+                    &[
+                        transform.translation.to_array().as_slice(),
+                        transform.rotation.to_array().as_slice(),
+                        transform.scale.to_array().as_slice(),
+                    ]
+                    .concat(),
+                ),
+                velocity: velocity.map(|v| hash_floats(&[v.linvel.x, v.linvel.y, v.angvel])),
+                sleeping: sleeping.map(|s| {
+                    let mut hasher = FixedState.build_hasher();
+                    hash_floats(&[s.normalized_linear_threshold, s.angular_threshold])
+                        .hash(&mut hasher);
+                    s.sleeping.hash(&mut hasher);
+                    hasher.finish()
+                }),
+                rigid_body: rigid_body
+                    .and_then(|h| rapier.bodies.get(h.0))
+                    .and_then(hash_serialized),
+                collider: collider
+                    .and_then(|h| rapier.colliders.get(h.0))
+                    .and_then(hash_serialized),
+            },
+        )
+        .collect::<Vec<_>>();
+    frame.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for body in frame.iter() {
+        log::info!("Body checksum on frame {}: {}", current_frame, body);
+    }
+
+    checksums.0.insert(current_frame, frame);
+    while checksums.0.len() > ROLLBACK_HISTORY_LEN {
+        checksums.0.pop_first();
+    }
+}
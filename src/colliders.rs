@@ -6,6 +6,7 @@ pub struct DynamicColliderBundle {
     pub collider_scale: ColliderScale,
     pub rigid_body: RigidBody,
     pub velocity: Velocity,
+    pub external_impulse: ExternalImpulse,
     pub locked_axes: LockedAxes,
     pub restitution: Restitution,
     pub friction: Friction,
@@ -21,6 +22,7 @@ impl Default for DynamicColliderBundle {
             collider_scale: ColliderScale::Absolute(Vec2::new(1., 1.)),
             rigid_body: RigidBody::Dynamic,
             velocity: Velocity::zero(),
+            external_impulse: ExternalImpulse::default(),
             locked_axes: LockedAxes::default(),
             restitution: Restitution::default(),
             friction: Friction::default(),
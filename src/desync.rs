@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+
+use bevy::utils::SystemTime;
+use bevy_ggrs::{LocalPlayers, RollbackFrameCount};
+use bevy_matchbox::prelude::PeerId;
+use clap::ValueEnum;
+
+use crate::prelude::*;
+
+/// How many frames of [`RollbackStatus`] we keep around for desync reports
+pub const ROLLBACK_HISTORY_LEN: usize = FPS * 2;
+
+/// Where desync reports are written, relative to the working directory
+pub const DESYNC_REPORT_DIR: &str = "desync_reports";
+
+/// How much extra work we do to help track down desyncs.  Each level does
+/// everything the ones before it do.  Reports are always written when a desync
+/// is detected; this only covers what happens every frame.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ValueEnum)]
+pub enum DesyncDiagnostics {
+    /// Nothing.  Saving serializes the context once, and rolling back
+    /// deserializes it once.
+    Off,
+    /// Log the checksums we already have as we save and roll back.  Costs a
+    /// log line, but no extra serializing or hashing.
+    #[default]
+    Checksums,
+    /// Also checksum every rollback entity, and its Rapier body and collider,
+    /// every frame.  Logged, and written into desync reports, so a desync can
+    /// be traced to the first body that diverged.
+    Bodies,
+    /// Also serialize and hash the context before and after every rollback, to
+    /// watch the state change in-flight.  This is expensive!
+    Rehash,
+}
+
+/// Sent when GGRS tells us our checksums disagree with a remote peer
+#[derive(Event, Debug, Clone, Copy)]
+pub struct DesyncEvent {
+    pub frame: Frame,
+    pub local_checksum: u128,
+    pub remote_checksum: u128,
+    pub addr: PeerId,
+}
+
+/// The last [`ROLLBACK_HISTORY_LEN`] rollback statuses, oldest first.  Like
+/// [`RollbackStatus`], this is left outside of the rollback system so we can
+/// see every resimulation that happened.
+#[derive(Default, Debug, Resource)]
+pub struct RollbackHistory(pub VecDeque<RollbackStatus>);
+
+/// Only the first desync of a match gets a report.  Once we have diverged,
+/// every following frame will probably be reported as well, and those reports
+/// are not interesting.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Resource)]
+pub struct DesyncReported(pub bool);
+
+pub fn record_rollback_history(
+    rollback_status: Res<RollbackStatus>,
+    mut history: ResMut<RollbackHistory>,
+) {
+    if history.0.len() >= ROLLBACK_HISTORY_LEN {
+        history.0.pop_front();
+    }
+    history.0.push_back(*rollback_status);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_desync_report(
+    mut events: EventReader<DesyncEvent>,
+    mut reported: ResMut<DesyncReported>,
+    local_players: Option<Res<LocalPlayers>>,
+    current_frame: Res<RollbackFrameCount>,
+    game_state: Res<PhysicsRollbackState>,
+    snapshots: Res<PhysicsSnapshots>,
+    history: Res<RollbackHistory>,
+    body_checksums: Res<BodyChecksums>,
+    enable_physics_after: Res<EnablePhysicsAfter>,
+    dynamic_spawns: Res<DynamicSpawns>,
+    rollback_rng: Res<RollbackRng>,
+    rollbackables: Query<
+        (
+            Entity,
+            Option<&Name>,
+            Option<&Transform>,
+            Option<&GlobalTransform>,
+            Option<&Velocity>,
+            Option<&Sleeping>,
+            Option<&AbilityCooldowns>,
+        ),
+        With<Rollback>,
+    >,
+) {
+    for event in events.read() {
+        error!(
+            "Desync detected on frame {} local {} remote {}@{:?}",
+            event.frame, event.local_checksum, event.remote_checksum, event.addr
+        );
+
+        if reported.0 {
+            continue;
+        }
+        reported.0 = true;
+
+        let current_frame: i32 = (*current_frame).into();
+        let handles = local_players
+            .as_ref()
+            .map(|p| p.0.clone())
+            .unwrap_or_default();
+
+        let summary = format!(
+            "desync frame: {}\n\
+             local checksum: {}\n\
+             remote checksum: {}\n\
+             remote peer: {:?}\n\
+             local handles: {:?}\n\
+             current frame: {}\n\
+             context hash: {}\n",
+            event.frame,
+            event.local_checksum,
+            event.remote_checksum,
+            event.addr,
+            handles,
+            current_frame,
+            game_state.checksum,
+        );
+
+        let history = history
+            .0
+            .iter()
+            .map(|s| format!("{:?}\n", s))
+            .collect::<String>();
+
+        // Sort by name so the reports from both peers line up when diffed
+        let mut entities = rollbackables.iter().collect::<Vec<_>>();
+        entities.sort_by_key(|(e, name, ..)| (name.map(|n| n.as_str().to_owned()), *e));
+        let mut components = format!(
+            "{:?}\n\n{:?}\n\n{:?}\n\n",
+            *enable_physics_after, *dynamic_spawns, *rollback_rng
+        );
+        for (e, name, transform, global_transform, velocity, sleeping, cooldowns) in entities {
+            components += &format!(
+                "{:?} {:?}\n  {:?}\n  {:?}\n  {:?}\n  {:?}\n  {:?}\n\n",
+                name, e, transform, global_transform, velocity, sleeping, cooldowns
+            );
+        }
+
+        let report = DesyncReport {
+            name: format!(
+                "{}-frame-{}-handles-{:?}",
+                timestamp(),
+                event.frame,
+                handles
+            ),
+            summary,
+            history,
+            components,
+            body_checksums: body_checksums.to_report(),
+            // The snapshot from the end of the latest frame we simulated
+            rapier_state: snapshots.sets(game_state.frame),
+        };
+        report.write();
+    }
+}
+
+/// Everything we know about our side of a desync.  This is the state of our
+/// world at the time GGRS reported the desync, which is usually a few frames
+/// after the frame that actually diverged.
+struct DesyncReport {
+    name: String,
+    summary: String,
+    history: String,
+    components: String,
+    /// Empty unless running with `--desync-diagnostics bodies`
+    body_checksums: String,
+    /// Each set of the snapshot, by name
+    rapier_state: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl DesyncReport {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn write(&self) {
+        let dir = std::path::Path::new(DESYNC_REPORT_DIR).join(&self.name);
+        let result = std::fs::create_dir_all(&dir)
+            .and_then(|_| std::fs::write(dir.join("summary.txt"), &self.summary))
+            .and_then(|_| std::fs::write(dir.join("rollback_history.txt"), &self.history))
+            .and_then(|_| std::fs::write(dir.join("components.txt"), &self.components))
+            .and_then(|_| std::fs::write(dir.join("body_checksums.txt"), &self.body_checksums))
+            .and_then(|_| {
+                self.rapier_state.iter().try_for_each(|(set, bytes)| {
+                    std::fs::write(dir.join(format!("rapier_{}.bin", set)), bytes)
+                })
+            });
+
+        match result {
+            Ok(_) => error!("Desync report written to {:?}", dir),
+            Err(e) => error!("Could not write desync report to {:?}: {}", dir, e),
+        }
+    }
+
+    /// No file system in the browser, so dump what we can to the console
+    #[cfg(target_arch = "wasm32")]
+    fn write(&self) {
+        error!(
+            "Desync report {}\n{}\n{}\n{}\n{}",
+            self.name, self.summary, self.history, self.components, self.body_checksums
+        );
+    }
+}
+
+/// Milliseconds since the epoch, which works in the browser too
+pub fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
@@ -0,0 +1,249 @@
+use bevy_ggrs::RollbackFrameCount;
+
+use crate::prelude::*;
+
+/// How many entities `respawn_all` keeps back from the deterministic pool for
+/// spawning during a match, i.e., the most dynamic bodies alive at once
+pub const DYNAMIC_SLOTS: usize = 16;
+
+/// With `--extra-balls`, a ball drops in this often, in frames
+pub const EXTRA_BALL_INTERVAL: Frame = FPS as Frame;
+
+/// How long an extra ball sticks around, in frames
+pub const EXTRA_BALL_LIFETIME: Frame = 2 * FPS as Frame;
+
+/// The entities set aside for dynamic bodies, in pool order.  These stay the
+/// same for the whole match, so this is not rolled back; only what is on them
+/// changes, see [`DynamicSpawns`].
+#[derive(Resource, Default, Debug)]
+pub struct DynamicPool(pub Vec<Entity>);
+
+/// What a dynamic body is, and so which components it gets
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Reflect)]
+pub enum DynamicKind {
+    Ball,
+}
+
+impl DynamicKind {
+    fn name(self) -> &'static str {
+        match self {
+            DynamicKind::Ball => "Extra Ball",
+        }
+    }
+
+    fn collider_bundle(self) -> DynamicColliderBundle {
+        match self {
+            // Same as the one we start with
+            DynamicKind::Ball => DynamicColliderBundle {
+                collider: Collider::ball(4.),
+                restitution: Restitution::coefficient(2.0),
+                ccd: Ccd::enabled(),
+                ..default()
+            },
+        }
+    }
+}
+
+/// A body spawned during the match.  Positions are whole units, so this can be
+/// hashed into the checksum.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Reflect)]
+pub struct DynamicBody {
+    pub kind: DynamicKind,
+    /// Where it starts out.  After that, its [`Transform`] is rolled back like
+    /// any other.
+    pub position: IVec2,
+    pub spawned_on: Frame,
+    /// Despawned by [`despawn_expired_bodies`] on this frame, if ever
+    pub despawn_on: Option<Frame>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Hash, Reflect)]
+pub struct DynamicSlot {
+    pub body: Option<DynamicBody>,
+    /// Rapier still has the old body until the end of the frame it was freed
+    /// on, so the slot is not handed out again until the next one
+    pub freed_on: Option<Frame>,
+}
+
+/// Which slots of the [`DynamicPool`] are in use, and by what.  Spawning and
+/// despawning only change this; the entities are brought in line by
+/// [`sync_dynamic_spawns`].  As it is rolled back, a rollback past a spawn
+/// frees its slot again, and a rollback past a despawn brings the body back.
+#[derive(Resource, Clone, Debug, Hash, PartialEq, Eq, Reflect)]
+#[reflect(Resource, Hash, PartialEq)]
+pub struct DynamicSpawns {
+    pub slots: Vec<DynamicSlot>,
+}
+
+impl Default for DynamicSpawns {
+    fn default() -> Self {
+        Self {
+            slots: vec![DynamicSlot::default(); DYNAMIC_SLOTS],
+        }
+    }
+}
+
+impl DynamicSpawns {
+    /// Puts `body` in the first free slot, which is the same one on every
+    /// peer.  `None` if every slot is in use.
+    pub fn spawn(&mut self, body: DynamicBody) -> Option<usize> {
+        let (index, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, s)| s.body.is_none() && s.freed_on != Some(body.spawned_on))?;
+        slot.body = Some(body);
+        Some(index)
+    }
+
+    pub fn despawn(&mut self, index: usize, frame: Frame) {
+        if let Some(slot) = self.slots.get_mut(index) {
+            if slot.body.take().is_some() {
+                slot.freed_on = Some(frame);
+            }
+        }
+    }
+
+    /// Every body alive, by slot
+    pub fn live(&self) -> impl Iterator<Item = (usize, &DynamicBody)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.body.as_ref().map(|b| (i, b)))
+    }
+}
+
+/// What a pool entity actually has on it right now, to compare with
+/// [`DynamicSpawns`]
+#[derive(Component, Copy, Clone, Debug)]
+pub struct SpawnedBody(pub DynamicBody);
+
+/// Everything a dynamic body has that a free pool entity does not.  Rapier adds
+/// the handles once it has the body.
+pub type DynamicBodyComponents = (
+    SpawnedBody,
+    DynamicColliderBundle,
+    TransformBundle,
+    Sleeping,
+    RapierRigidBodyHandle,
+    RapierColliderHandle,
+);
+
+pub fn extra_balls_enabled(args: Res<Args>) -> bool {
+    args.extra_balls
+}
+
+pub fn despawn_expired_bodies(
+    current_frame: Res<RollbackFrameCount>,
+    mut spawns: ResMut<DynamicSpawns>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+
+    let expired = spawns
+        .live()
+        .filter(|(_, b)| b.despawn_on.is_some_and(|f| f <= current_frame))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    for index in expired {
+        log::info!("Despawning slot {} on frame {}", index, current_frame);
+        spawns.despawn(index, current_frame);
+    }
+}
+
+/// Drops a ball in from somewhere along the ceiling every
+/// [`EXTRA_BALL_INTERVAL`] frames, to show off [`DynamicSpawns`]
+pub fn spawn_extra_balls(
+    current_frame: Res<RollbackFrameCount>,
+    physics_enabled: Res<PhysicsEnabled>,
+    mut rollback_rng: ResMut<RollbackRng>,
+    mut spawns: ResMut<DynamicSpawns>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    if !physics_enabled.0 || current_frame % EXTRA_BALL_INTERVAL != 0 {
+        return;
+    }
+
+    let body = DynamicBody {
+        kind: DynamicKind::Ball,
+        position: IVec2::new(rollback_rng.gen_range(-150..=150), 150),
+        spawned_on: current_frame,
+        despawn_on: Some(current_frame + EXTRA_BALL_LIFETIME),
+    };
+    match spawns.spawn(body) {
+        Some(index) => log::info!("Spawning {:?} in slot {}", body, index),
+        None => log::info!("No free slot for an extra ball on frame {}", current_frame),
+    }
+}
+
+/// Brings the pool entities in line with [`DynamicSpawns`].  Runs twice a
+/// frame: right after a rollback, to undo (or redo) whatever spawned or
+/// despawned since, and after our game systems, for this frame's own spawns and
+/// despawns.
+///
+/// Rapier's context is rolled back separately, so after a rollback the bodies
+/// we bring back are already in it.  Those get their old handles back rather
+/// than having Rapier make new ones.
+pub fn sync_dynamic_spawns(
+    mut commands: Commands,
+    spawns: Res<DynamicSpawns>,
+    pool: Res<DynamicPool>,
+    rapier: Res<RapierContext>,
+    entities: Query<(
+        &DeterministicSpawn,
+        Option<&SpawnedBody>,
+        Option<&Transform>,
+        Option<&Velocity>,
+    )>,
+) {
+    for (index, (slot, &entity)) in spawns.slots.iter().zip(pool.0.iter()).enumerate() {
+        let Ok((spawn, spawned, transform, velocity)) = entities.get(entity) else {
+            continue;
+        };
+        if slot.body == spawned.map(|s| s.0) {
+            continue;
+        }
+
+        let Some(body) = slot.body else {
+            // Removing the handles is what tells Rapier to remove the body,
+            // if it still has it
+            commands
+                .entity(entity)
+                .remove::<DynamicBodyComponents>()
+                .insert(Name::new(format!("Deterministic Spawn {}", spawn.index)));
+            continue;
+        };
+
+        // A body brought back by a rollback already had its transform and
+        // velocity rolled back with it
+        let transform = transform.copied().unwrap_or_else(|| {
+            Transform::from_xyz(body.position.x as f32, body.position.y as f32, 0.)
+        });
+        let mut collider_bundle = body.kind.collider_bundle();
+        if let Some(velocity) = velocity {
+            collider_bundle.velocity = *velocity;
+        }
+
+        // Inserting over a body that was already there, rather than removing
+        // it first, so Rapier does not see its handles go away
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.insert((
+            SpawnedBody(body),
+            Name::new(format!("{} {}", body.kind.name(), index)),
+            collider_bundle,
+            // Rapier places new bodies by their global transform, and there is
+            // no propagation between here and there
+            TransformBundle {
+                local: transform,
+                global: GlobalTransform::from(transform),
+            },
+        ));
+        match rapier.entity2body().get(&entity) {
+            Some(handle) => entity_commands.insert(RapierRigidBodyHandle(*handle)),
+            None => entity_commands.remove::<RapierRigidBodyHandle>(),
+        };
+        match rapier.entity2collider().get(&entity) {
+            Some(handle) => entity_commands.insert(RapierColliderHandle(*handle)),
+            None => entity_commands.remove::<RapierColliderHandle>(),
+        };
+    }
+}
@@ -13,9 +13,19 @@ pub struct RollbackStatus {
     pub is_rollback: bool,
     pub is_replay: bool,
     pub rollback_frame: Frame,
+    /// How many frames the latest rollback resimulates
+    pub rollback_depth: Frame,
     pub last_frame: Frame,
 }
 
+/// Puts the GGRS frame counters back to the start.  A fresh session always
+/// begins at frame 0, so these must follow it when we start a new match in the
+/// same world.
+pub fn reset_frame_counts(mut commands: Commands) {
+    commands.insert_resource(RollbackFrameCount::default());
+    commands.insert_resource(ConfirmedFrameCount::default());
+}
+
 pub fn log_confirmed_frame(confirmed_frame: Res<ConfirmedFrameCount>) {
     let confirmed_frame: i32 = (*confirmed_frame).into();
     log::info!("confirmed frame: {}", confirmed_frame);
@@ -26,6 +36,30 @@ pub fn log_start_frame(current_frame: Res<RollbackFrameCount>) {
     log::info!("---- start frame {} ----", current_frame);
 }
 
+/// Logged only once the log lines know whether this frame is a resimulation,
+/// like everything else this frame
+pub fn log_frame_status(
+    current_frame: Res<RollbackFrameCount>,
+    current_session_frame: Res<CurrentSessionFrame>,
+    rollback_status: Res<RollbackStatus>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+
+    log::info!("current session frame: {}", current_session_frame.0);
+
+    if rollback_status.is_rollback {
+        log::info!(
+            "rollback on {} to {}",
+            rollback_status.rollback_frame + rollback_status.rollback_depth - 1,
+            rollback_status.rollback_frame,
+        );
+    }
+
+    if rollback_status.is_replay {
+        log::info!("replay on {} of {}", current_session_frame.0, current_frame);
+    }
+}
+
 pub fn log_end_frame(current_frame: Res<RollbackFrameCount>) {
     let current_frame: i32 = (*current_frame).into();
     log::info!("----- end frame {} -----", current_frame);
@@ -45,8 +79,6 @@ pub fn update_current_session_frame(
             Session::Spectator(_) => current_session_frame.0 = current_frame,
         }
     }
-
-    log::info!("current session frame: {}", current_session_frame.0);
 }
 
 pub fn update_rollback_status(
@@ -64,15 +96,7 @@ pub fn update_rollback_status(
 
     if rollback_status.is_rollback {
         rollback_status.rollback_frame = current_frame;
-        log::info!(
-            "rollback on {} to {}",
-            rollback_status.last_frame,
-            rollback_status.rollback_frame,
-        );
-    }
-
-    if rollback_status.is_replay {
-        log::info!("replay on {} of {}", current_session_frame.0, current_frame);
+        rollback_status.rollback_depth = rollback_status.last_frame - current_frame + 1;
     }
 
     // I know this seems silly at first glance, but after we know we've entered
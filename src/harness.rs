@@ -0,0 +1,622 @@
+//! Runs several peers in one process, wired together over an in-memory socket, so
+//! we can check that both sides agree on the physics state of every confirmed
+//! frame without a matchbox, a second window, or a real network.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::Duration;
+use bevy_ggrs::{ConfirmedFrameCount, LocalPlayers, RollbackFrameCount};
+use bevy_matchbox::prelude::PeerId;
+use clap::Parser;
+use ggrs::{Message, NonBlockingSocket};
+use rand::{rngs::StdRng, SeedableRng};
+use uuid::Uuid;
+
+use crate::prelude::*;
+use crate::{
+    add_headless_plugins, add_peer_left_reset, add_simulation, spawn_deterministic_pool,
+    ExampleSystemSets,
+};
+
+/// How badly our in-memory network behaves
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NetworkConditions {
+    /// How many harness ticks a message spends in flight
+    pub latency_ticks: u64,
+    /// Chance that any one message is dropped, from 0 to 1
+    pub loss: f64,
+    /// How long GGRS waits on a silent peer before giving up on it, if not its
+    /// own default.  This one is wall-clock time, GGRS measures it itself.
+    pub disconnect_timeout: Option<Duration>,
+}
+
+impl NetworkConditions {
+    /// Every message is `latency_ticks` late, and none are lost
+    pub fn latency(latency_ticks: u64) -> Self {
+        Self {
+            latency_ticks,
+            ..default()
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: u64,
+    from: PeerId,
+    bytes: Vec<u8>,
+}
+
+struct NetworkState {
+    tick: u64,
+    conditions: NetworkConditions,
+    /// Nothing gets through while this is set
+    partitioned: bool,
+    // Seeded so a failing run can be reproduced
+    rng: StdRng,
+    inboxes: BTreeMap<PeerId, VecDeque<InFlight>>,
+}
+
+/// A tiny network that only lives in memory.  Time is measured in harness
+/// ticks rather than wall-clock time, so latency is the same on every machine.
+#[derive(Clone)]
+pub struct MemoryNetwork(Arc<Mutex<NetworkState>>);
+
+impl MemoryNetwork {
+    pub fn new(conditions: NetworkConditions) -> Self {
+        Self(Arc::new(Mutex::new(NetworkState {
+            tick: 0,
+            conditions,
+            partitioned: false,
+            rng: StdRng::seed_from_u64(0),
+            inboxes: BTreeMap::new(),
+        })))
+    }
+
+    pub fn socket(&self, id: PeerId) -> MemorySocket {
+        MemorySocket {
+            id,
+            network: self.clone(),
+        }
+    }
+
+    pub fn tick(&self) {
+        self.0.lock().unwrap().tick += 1;
+    }
+
+    /// Cuts every peer off from every other, dropping whatever is in flight,
+    /// or lets them talk again
+    pub fn set_partitioned(&self, partitioned: bool) {
+        let mut network = self.0.lock().unwrap();
+        network.partitioned = partitioned;
+        if partitioned {
+            network.inboxes.clear();
+        }
+    }
+
+    fn conditions(&self) -> NetworkConditions {
+        self.0.lock().unwrap().conditions
+    }
+}
+
+/// One peer's end of a [`MemoryNetwork`]
+pub struct MemorySocket {
+    id: PeerId,
+    network: MemoryNetwork,
+}
+
+impl NonBlockingSocket<PeerId> for MemorySocket {
+    fn send_to(&mut self, msg: &Message, addr: &PeerId) {
+        let mut network = self.network.0.lock().unwrap();
+
+        let loss = network.conditions.loss;
+        if network.partitioned || (loss > 0. && network.rng.gen_bool(loss)) {
+            return;
+        }
+
+        // Go through the same encoding a real socket would
+        let bytes = bincode::serialize(msg).expect("Could not serialize message");
+        let deliver_at = network.tick + network.conditions.latency_ticks;
+        network
+            .inboxes
+            .entry(*addr)
+            .or_default()
+            .push_back(InFlight {
+                deliver_at,
+                from: self.id,
+                bytes,
+            });
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        let mut network = self.network.0.lock().unwrap();
+        let tick = network.tick;
+        let Some(inbox) = network.inboxes.get_mut(&self.id) else {
+            return Vec::new();
+        };
+
+        // Latency is the same for every message, so they arrive in order
+        let mut received = Vec::new();
+        while inbox.front().is_some_and(|m| m.deliver_at <= tick) {
+            let m = inbox.pop_front().unwrap();
+            let msg = bincode::deserialize(&m.bytes).expect("Could not deserialize message");
+            received.push((m.from, msg));
+        }
+        received
+    }
+}
+
+/// The physics checksum each frame ended with.  Resimulated frames overwrite
+/// their earlier, predicted, entry, so once a frame is confirmed its entry is
+/// final.
+#[derive(Default, Resource)]
+pub struct ChecksumLog(pub BTreeMap<Frame, u64>);
+
+pub fn record_checksum(
+    current_frame: Res<RollbackFrameCount>,
+    game_state: Res<PhysicsRollbackState>,
+    mut log: ResMut<ChecksumLog>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    log.0.insert(current_frame, game_state.checksum);
+}
+
+/// Every player's [`AbilityCooldowns`] at the end of each frame, by handle.
+/// Overwritten by resimulated frames, like [`ChecksumLog`].
+#[derive(Default, Resource)]
+pub struct CooldownLog(pub BTreeMap<Frame, Vec<AbilityCooldowns>>);
+
+pub fn record_cooldowns(
+    current_frame: Res<RollbackFrameCount>,
+    players: Query<(&Player, &AbilityCooldowns)>,
+    mut log: ResMut<CooldownLog>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    let mut players = players.iter().collect::<Vec<_>>();
+    players.sort_by_key(|(p, _)| p.handle);
+    log.0.insert(
+        current_frame,
+        players.into_iter().map(|(_, c)| *c).collect(),
+    );
+}
+
+/// Every rollback we went through, as [`RollbackStatus`] saw it on the first
+/// frame we went back to
+#[derive(Default, Resource)]
+pub struct RollbackLog(pub Vec<RollbackStatus>);
+
+pub fn record_rollbacks(rollback_status: Res<RollbackStatus>, mut log: ResMut<RollbackLog>) {
+    if rollback_status.is_rollback {
+        log.0.push(*rollback_status);
+    }
+}
+
+/// Every frame a dynamic body was spawned or despawned on
+#[derive(Default, Resource)]
+pub struct SpawnFrameLog(pub BTreeSet<Frame>);
+
+pub fn record_spawn_frames(spawns: Res<DynamicSpawns>, mut log: ResMut<SpawnFrameLog>) {
+    for (_, body) in spawns.live() {
+        log.0.insert(body.spawned_on);
+        log.0.extend(body.despawn_on);
+    }
+}
+
+/// Counts every desync GGRS reports to us
+#[derive(Default, Resource)]
+pub struct DesyncCount(pub usize);
+
+pub fn count_desyncs(mut events: EventReader<DesyncEvent>, mut count: ResMut<DesyncCount>) {
+    count.0 += events.read().count();
+}
+
+/// Everything a peer needs to start a P2P session over our in-memory network,
+/// so it can start another one after a disconnect, like `connect` would
+#[derive(Resource, Clone)]
+pub struct MemoryConnection {
+    pub local_handle: usize,
+    pub ids: Vec<PeerId>,
+    pub network: MemoryNetwork,
+}
+
+impl MemoryConnection {
+    fn start_session(&self) -> Session<ExampleGgrsConfig> {
+        let mut session_build = session_builder(self.ids.len())
+            .with_desync_detection_mode(ggrs::DesyncDetection::On { interval: 1 });
+        if let Some(timeout) = self.network.conditions().disconnect_timeout {
+            session_build = session_build
+                .with_disconnect_timeout(timeout)
+                .with_disconnect_notify_delay(timeout / 2);
+        }
+        for (handle, id) in self.ids.iter().enumerate() {
+            let player = if handle == self.local_handle {
+                PlayerType::Local
+            } else {
+                PlayerType::Remote(*id)
+            };
+            session_build = session_build
+                .add_player(player, handle)
+                .expect("Invalid player added.");
+        }
+        let session = session_build
+            .start_p2p_session(self.network.socket(self.ids[self.local_handle]))
+            .expect("Session could not be created.");
+        Session::P2P(session)
+    }
+}
+
+/// Our stand-in for `connect` when the world is reset after a disconnect
+pub fn reconnect_memory(mut commands: Commands, connection: Res<MemoryConnection>) {
+    commands.insert_resource(LocalPlayers(vec![connection.local_handle]));
+    commands.insert_resource(connection.start_session());
+}
+
+/// Builds the same app `main` does, minus the window and the matchbox, with a
+/// P2P session over our in-memory socket already in place.
+pub fn peer_app(connection: MemoryConnection) -> App {
+    let args = Args::parse_from(["harness", "--players", &connection.ids.len().to_string()]);
+
+    let mut app = App::new();
+    spawn_deterministic_pool(&mut app);
+    // We drive the updates ourselves, so how long the runner waits does not
+    // matter
+    add_headless_plugins(&mut app, Duration::ZERO);
+    add_simulation(&mut app);
+    add_peer_left_reset(&mut app, reconnect_memory);
+
+    // Every update is exactly one GGRS frame of time
+    app.insert_resource(args)
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1. / FPS as f64,
+        )))
+        .init_resource::<ChecksumLog>()
+        .init_resource::<CooldownLog>()
+        .init_resource::<RollbackLog>()
+        .init_resource::<SpawnFrameLog>()
+        .init_resource::<DesyncCount>()
+        .add_event::<DesyncEvent>()
+        .add_systems(Update, (handle_p2p_events, count_desyncs).chain())
+        .add_systems(
+            bevy_ggrs::GgrsSchedule,
+            record_rollbacks
+                .after(update_rollback_status)
+                .in_set(ExampleSystemSets::Rollback),
+        )
+        .add_systems(
+            bevy_ggrs::GgrsSchedule,
+            (record_checksum, record_cooldowns, record_spawn_frames)
+                .after(save_rapier_context)
+                .in_set(ExampleSystemSets::SaveAndChecksum),
+        );
+
+    app.insert_resource(LocalPlayers(vec![connection.local_handle]))
+        .insert_resource(connection.start_session())
+        .insert_resource(connection);
+
+    app.finish();
+    app.cleanup();
+    app
+}
+
+/// A headless app playing `replay` back as fast as it can, the way
+/// `--mode replay --headless --replay-speed max` would
+pub fn replay_app(replay: Replay) -> App {
+    let mut args = Args::parse_from(["harness", "--mode", "replay"]);
+    replay.header.apply(&mut args);
+
+    let mut app = App::new();
+    spawn_deterministic_pool(&mut app);
+    add_headless_plugins(&mut app, Duration::ZERO);
+    add_simulation(&mut app);
+    add_replay_playback(&mut app, ReplayPlayback::new(replay), ReplaySpeed::Max);
+
+    // `connect` starts the local session, as it would in the real thing
+    app.insert_resource(args).add_systems(Startup, connect);
+
+    app.finish();
+    app.cleanup();
+    app
+}
+
+/// A handful of peers, one per player, and the network between them
+pub struct Peers {
+    pub network: MemoryNetwork,
+    pub peers: Vec<App>,
+}
+
+impl Peers {
+    pub fn new(num_players: usize, conditions: NetworkConditions) -> Self {
+        let network = MemoryNetwork::new(conditions);
+        let ids = (0..num_players as u128)
+            .map(|i| PeerId(Uuid::from_u128(i + 1)))
+            .collect::<Vec<_>>();
+        let peers = (0..num_players)
+            .map(|local_handle| {
+                peer_app(MemoryConnection {
+                    local_handle,
+                    ids: ids.clone(),
+                    network: network.clone(),
+                })
+            })
+            .collect();
+        Self { network, peers }
+    }
+
+    pub fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.network.tick();
+            for peer in self.peers.iter_mut() {
+                peer.update();
+            }
+        }
+    }
+
+    /// Holds down `keys` on `peer`'s keyboard, and lets go of everything else
+    pub fn hold(&mut self, peer: usize, keys: &[KeyCode]) {
+        let mut keyboard = self.peers[peer]
+            .world_mut()
+            .resource_mut::<ButtonInput<KeyCode>>();
+        keyboard.release_all();
+        for key in keys {
+            keyboard.press(*key);
+        }
+    }
+
+    /// Runs until `done` holds, for timeouts GGRS measures in wall-clock time.
+    /// Panics after `limit`.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut(&Self) -> bool) {
+        let started = bevy::utils::Instant::now();
+        while !done(self) {
+            assert!(started.elapsed() < limit, "Gave up after {:?}", limit);
+            self.run(1);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// The newest frame every peer has confirmed
+    pub fn confirmed_frame(&self) -> Frame {
+        self.peers
+            .iter()
+            .map(|p| i32::from(*p.world().resource::<ConfirmedFrameCount>()))
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Runs `ticks` more, then checks that the match got past the load screen
+    /// and every peer agrees on every confirmed frame.  Tests check their own
+    /// feature after that.
+    pub fn run_and_assert_agree(&mut self, ticks: usize) {
+        self.run(ticks);
+        assert!(
+            self.confirmed_frame() > (FPS * LOAD_SECONDS) as Frame,
+            "Only confirmed up to frame {}",
+            self.confirmed_frame()
+        );
+        self.assert_checksums_agree();
+    }
+
+    pub fn assert_checksums_agree(&self) {
+        let confirmed = self.confirmed_frame();
+        let logs = self
+            .peers
+            .iter()
+            .map(|p| &p.world().resource::<ChecksumLog>().0)
+            .collect::<Vec<_>>();
+
+        // Stop short of the confirmed frame itself, in case one peer has only
+        // predicted it so far
+        for frame in 0..confirmed {
+            let checksums = logs.iter().map(|log| log.get(&frame)).collect::<Vec<_>>();
+            assert!(
+                checksums.windows(2).all(|w| w[0] == w[1]),
+                "Checksums differ on confirmed frame {}: {:?}",
+                frame,
+                checksums
+            );
+        }
+
+        for peer in self.peers.iter() {
+            assert_eq!(peer.world().resource::<DesyncCount>().0, 0);
+        }
+    }
+
+    pub fn assert_cooldowns_agree(&self) {
+        let confirmed = self.confirmed_frame();
+        let logs = self
+            .peers
+            .iter()
+            .map(|p| &p.world().resource::<CooldownLog>().0)
+            .collect::<Vec<_>>();
+
+        for frame in 0..confirmed {
+            let cooldowns = logs.iter().map(|log| log.get(&frame)).collect::<Vec<_>>();
+            assert!(
+                cooldowns.windows(2).all(|w| w[0] == w[1]),
+                "Cooldowns differ on confirmed frame {}: {:?}",
+                frame,
+                cooldowns
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Long enough to get past the load screen and the first physics pause
+    const TICKS: usize = FPS * (LOAD_SECONDS + 4);
+
+    #[test]
+    fn peers_agree_on_a_perfect_network() {
+        let mut peers = Peers::new(2, NetworkConditions::default());
+        peers.run_and_assert_agree(TICKS);
+    }
+
+    #[test]
+    fn peers_agree_with_latency_and_loss() {
+        let mut peers = Peers::new(
+            2,
+            NetworkConditions {
+                latency_ticks: 4,
+                loss: 0.05,
+                ..default()
+            },
+        );
+        peers.run_and_assert_agree(TICKS);
+    }
+
+    #[test]
+    fn peers_agree_on_abilities_with_latency() {
+        let mut peers = Peers::new(
+            2,
+            NetworkConditions {
+                latency_ticks: 4,
+                loss: 0.05,
+                ..default()
+            },
+        );
+
+        // Each player goes through jump, dash and kick, half a second each and
+        // out of step with the other, so the remote one is mispredicted every
+        // time it switches.  One of them dashes diagonally.
+        let abilities = [KeyCode::Space, KeyCode::ShiftLeft, KeyCode::KeyF];
+        let directions: [&[KeyCode]; 2] = [&[KeyCode::KeyD, KeyCode::KeyW], &[KeyCode::KeyA]];
+        for tick in 0..TICKS {
+            for (peer, direction) in directions.iter().enumerate() {
+                let ability = abilities[(tick / (FPS / 2) + peer) % abilities.len()];
+                peers.hold(peer, &[&[ability], *direction].concat());
+            }
+            peers.run(1);
+        }
+
+        // Let go, so the last of it gets confirmed
+        for peer in 0..peers.peers.len() {
+            peers.hold(peer, &[]);
+        }
+        peers.run_and_assert_agree(FPS);
+        peers.assert_cooldowns_agree();
+
+        // Every player used every ability, or there was nothing to agree on.
+        // An ability's cooldown only starts out full on the frame it is used.
+        let log = &peers.peers[0].world().resource::<CooldownLog>().0;
+        for handle in 0..peers.peers.len() {
+            let used = |f: fn(&AbilityCooldowns) -> bool| {
+                log.values()
+                    .any(|cooldowns| cooldowns.get(handle).is_some_and(f))
+            };
+            assert!(used(|c| c.jump == JUMP_COOLDOWN), "{} never jumped", handle);
+            assert!(used(|c| c.dash == DASH_COOLDOWN), "{} never dashed", handle);
+            assert!(used(|c| c.kick == KICK_COOLDOWN), "{} never kicked", handle);
+        }
+    }
+
+    #[test]
+    fn more_than_two_peers_agree() {
+        let mut peers = Peers::new(MAX_PLAYERS, NetworkConditions::latency(2));
+        peers.run_and_assert_agree(TICKS);
+    }
+
+    #[test]
+    fn peers_reconnect_after_disconnects_in_a_row() {
+        let timeout = Duration::from_millis(200);
+        let mut peers = Peers::new(
+            2,
+            NetworkConditions {
+                disconnect_timeout: Some(timeout),
+                ..default()
+            },
+        );
+        peers.run(FPS * LOAD_SECONDS);
+
+        // Every disconnect has to reset the match again, not just the first
+        for disconnect in 1..=2 {
+            peers.network.set_partitioned(true);
+            peers.run_until(timeout * 10, |peers| {
+                peers
+                    .peers
+                    .iter()
+                    .all(|p| p.world().contains_resource::<PeerLeft>())
+            });
+
+            // Sync requests sent while cut off are retried, also on a timer
+            peers.network.set_partitioned(false);
+            peers.run_until(timeout * 10, |peers| {
+                peers
+                    .peers
+                    .iter()
+                    .all(|p| !p.world().contains_resource::<PeerLeft>())
+            });
+            info!("Reconnected after disconnect {}", disconnect);
+        }
+
+        // And the match after that plays out like any other
+        peers.run_and_assert_agree(TICKS);
+    }
+
+    #[test]
+    fn peers_agree_with_dynamic_spawns() {
+        let mut peers = Peers::new(2, NetworkConditions::latency(4));
+        for peer in peers.peers.iter_mut() {
+            peer.world_mut().resource_mut::<Args>().extra_balls = true;
+        }
+        peers.run_and_assert_agree(TICKS);
+
+        // Balls came and went
+        let spawns = peers.peers[0].world().resource::<DynamicSpawns>();
+        assert!(spawns.live().count() > 0, "Nothing is spawned");
+        assert!(
+            spawns.slots.iter().any(|s| s.freed_on.is_some()),
+            "Nothing was despawned"
+        );
+
+        // And some of that had to be undone and done again.  The random input
+        // `startup` turns on keeps the remote player mispredicted, and a
+        // rollback to `rollback_frame` resimulates `rollback_depth` frames
+        // from there.
+        for peer in peers.peers.iter() {
+            let spawn_frames = &peer.world().resource::<SpawnFrameLog>().0;
+            let rollbacks = &peer.world().resource::<RollbackLog>().0;
+            assert!(!spawn_frames.is_empty());
+            assert!(
+                rollbacks.iter().any(|r| spawn_frames
+                    .range(r.rollback_frame..r.rollback_frame + r.rollback_depth)
+                    .next()
+                    .is_some()),
+                "None of {} rollbacks went back past a spawn or despawn",
+                rollbacks.len()
+            );
+        }
+    }
+
+    #[test]
+    fn replays_reproduce_recorded_checksums() {
+        let mut peers = Peers::new(2, NetworkConditions::latency(2));
+        for peer in peers.peers.iter_mut() {
+            // Only needs to be set to record, nothing is written until exit
+            peer.world_mut().resource_mut::<Args>().replay_dir = Some("replays".into());
+        }
+        peers.run_and_assert_agree(TICKS);
+
+        let peer = &peers.peers[0];
+        let replay = peer
+            .world()
+            .resource::<ReplayRecording>()
+            .to_replay(peer.world().resource::<Args>(), peers.confirmed_frame())
+            .expect("Nothing was recorded");
+        let frames = replay.frames.len();
+
+        let mut app = replay_app(replay);
+        for _ in 0..frames * 2 {
+            app.update();
+            if app.world().resource::<ReplayPlayback>().finished {
+                break;
+            }
+        }
+
+        let playback = app.world().resource::<ReplayPlayback>();
+        assert!(playback.finished, "Replay did not finish");
+        assert_eq!(playback.mismatches, 0);
+    }
+}
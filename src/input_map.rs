@@ -0,0 +1,385 @@
+use std::path::Path;
+
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// Something a player can do, and so something a key or button can be bound to
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    Up,
+    Down,
+    Left,
+    Right,
+    Jump,
+    Dash,
+    Kick,
+    RandomOn,
+    RandomOff,
+}
+
+impl Action {
+    /// In the order the rebinding screen lists them
+    pub const ALL: [Action; 9] = [
+        Action::Up,
+        Action::Down,
+        Action::Left,
+        Action::Right,
+        Action::Jump,
+        Action::Dash,
+        Action::Kick,
+        Action::RandomOn,
+        Action::RandomOff,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Up => "up",
+            Action::Down => "down",
+            Action::Left => "left",
+            Action::Right => "right",
+            Action::Jump => "jump",
+            Action::Dash => "dash",
+            Action::Kick => "kick",
+            Action::RandomOn => "random input on",
+            Action::RandomOff => "random input off",
+        }
+    }
+}
+
+/// The keys and gamepad buttons bound to one [`Action`].  Any of them will do.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Binding {
+    pub keys: Vec<KeyCode>,
+    pub buttons: Vec<GamepadButtonType>,
+}
+
+impl Binding {
+    fn new(key: KeyCode, button: Option<GamepadButtonType>) -> Self {
+        Self {
+            keys: vec![key],
+            buttons: button.into_iter().collect(),
+        }
+    }
+
+    /// Held on the keyboard, or on `gamepad` if the player has one
+    pub fn pressed(
+        &self,
+        keys: &ButtonInput<KeyCode>,
+        buttons: &ButtonInput<GamepadButton>,
+        gamepad: Option<Gamepad>,
+    ) -> bool {
+        keys.any_pressed(self.keys.iter().copied())
+            || gamepad.is_some_and(|gamepad| {
+                buttons.any_pressed(self.buttons.iter().map(|b| GamepadButton::new(gamepad, *b)))
+            })
+    }
+
+    /// Pressed this update, on the keyboard or any gamepad
+    pub fn just_pressed(
+        &self,
+        keys: &ButtonInput<KeyCode>,
+        buttons: &ButtonInput<GamepadButton>,
+    ) -> bool {
+        keys.any_just_pressed(self.keys.iter().copied())
+            || buttons
+                .get_just_pressed()
+                .any(|b| self.buttons.contains(&b.button_type))
+    }
+}
+
+impl std::fmt::Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bound = self
+            .keys
+            .iter()
+            .map(|k| format!("{:?}", k))
+            .chain(self.buttons.iter().map(|b| format!("{:?}", b)))
+            .collect::<Vec<_>>();
+        if bound.is_empty() {
+            write!(f, "(nothing)")
+        } else {
+            write!(f, "{}", bound.join(", "))
+        }
+    }
+}
+
+/// What each [`Action`] is bound to.  Read from `--input-map` at startup, and
+/// written back there when changed on the rebinding screen.  This is only about
+/// how we read our own input; peers can bind things however they like, as only
+/// the resulting [`GGRSInput`] is sent.
+#[derive(Serialize, Deserialize, Resource, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct InputMap {
+    pub up: Binding,
+    pub down: Binding,
+    pub left: Binding,
+    pub right: Binding,
+    pub jump: Binding,
+    pub dash: Binding,
+    pub kick: Binding,
+    pub random_on: Binding,
+    pub random_off: Binding,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        Self {
+            up: Binding::new(KeyCode::KeyW, Some(GamepadButtonType::DPadUp)),
+            down: Binding::new(KeyCode::KeyS, Some(GamepadButtonType::DPadDown)),
+            left: Binding::new(KeyCode::KeyA, Some(GamepadButtonType::DPadLeft)),
+            right: Binding::new(KeyCode::KeyD, Some(GamepadButtonType::DPadRight)),
+            jump: Binding::new(KeyCode::Space, Some(GamepadButtonType::South)),
+            dash: Binding::new(KeyCode::ShiftLeft, Some(GamepadButtonType::West)),
+            kick: Binding::new(KeyCode::KeyF, Some(GamepadButtonType::East)),
+            random_on: Binding::new(KeyCode::KeyR, None),
+            random_off: Binding::new(KeyCode::KeyT, None),
+        }
+    }
+}
+
+impl InputMap {
+    pub fn binding(&self, action: Action) -> &Binding {
+        match action {
+            Action::Up => &self.up,
+            Action::Down => &self.down,
+            Action::Left => &self.left,
+            Action::Right => &self.right,
+            Action::Jump => &self.jump,
+            Action::Dash => &self.dash,
+            Action::Kick => &self.kick,
+            Action::RandomOn => &self.random_on,
+            Action::RandomOff => &self.random_off,
+        }
+    }
+
+    pub fn binding_mut(&mut self, action: Action) -> &mut Binding {
+        match action {
+            Action::Up => &mut self.up,
+            Action::Down => &mut self.down,
+            Action::Left => &mut self.left,
+            Action::Right => &mut self.right,
+            Action::Jump => &mut self.jump,
+            Action::Dash => &mut self.dash,
+            Action::Kick => &mut self.kick,
+            Action::RandomOn => &mut self.random_on,
+            Action::RandomOff => &mut self.random_off,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        ron::from_str(&text).map_err(|e| format!("Bad input map {:?}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text =
+            ron::ser::to_string_pretty(self, PrettyConfig::default()).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| format!("Could not write {:?}: {}", path, e))
+    }
+}
+
+/// Swaps the default bindings for the ones in `--input-map`, if it exists yet.
+/// A missing file is fine; rebinding anything will create it.
+pub fn load_input_map(mut commands: Commands, args: Res<Args>) {
+    let Some(path) = &args.input_map else {
+        return;
+    };
+
+    if !path.exists() {
+        info!("No input map at {:?} yet, using the default bindings", path);
+        return;
+    }
+
+    match InputMap::load(path) {
+        Ok(input_map) => {
+            info!("Input map loaded from {:?}", path);
+            commands.insert_resource(input_map);
+        }
+        Err(e) => error!("{}, using the default bindings", e),
+    }
+}
+
+/// The rebinding screen, toggled with F1.  Arrow keys pick an action, enter
+/// waits for the next key or gamepad button to bind to it, and backspace
+/// clears it.
+#[derive(Resource, Default, Debug)]
+pub struct RebindScreen {
+    pub visible: bool,
+    pub selected: usize,
+    /// Waiting for a key or button for the selected action
+    pub waiting: bool,
+    /// Changed since the screen was opened, so there is something to save
+    pub changed: bool,
+}
+
+/// Marker for the rebinding screen text
+#[derive(Component)]
+pub struct RebindText;
+
+pub fn spawn_rebind_screen(mut commands: Commands) {
+    commands.spawn((
+        RebindText,
+        TextBundle {
+            // Closed until F1 is pressed
+            visibility: Visibility::Hidden,
+            ..TextBundle::from_section("", TextStyle::default()).with_style(Style {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.),
+                left: Val::Px(10.),
+                ..default()
+            })
+        },
+    ));
+}
+
+/// Non-game input, like [`toggle_random_input`].  While the screen is open,
+/// [`input`] ignores our bindings so nobody moves while choosing keys.
+pub fn rebind_input(
+    keys: Res<ButtonInput<KeyCode>>,
+    buttons: Res<ButtonInput<GamepadButton>>,
+    args: Res<Args>,
+    mut screen: ResMut<RebindScreen>,
+    mut input_map: ResMut<InputMap>,
+    mut text: Query<&mut Visibility, With<RebindText>>,
+) {
+    if screen.waiting {
+        // Whatever is pressed first is the new binding, so F1 and the arrow
+        // keys can be bound too
+        let action = Action::ALL[screen.selected];
+        if let Some(key) = keys.get_just_pressed().next() {
+            input_map.binding_mut(action).keys = vec![*key];
+        } else if let Some(button) = buttons.get_just_pressed().next() {
+            input_map.binding_mut(action).buttons = vec![button.button_type];
+        } else {
+            return;
+        }
+        screen.waiting = false;
+        screen.changed = true;
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::F1) {
+        screen.visible = !screen.visible;
+        for mut visibility in text.iter_mut() {
+            *visibility = if screen.visible {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+
+        // Save when closing, rather than after every change
+        if !screen.visible && screen.changed {
+            screen.changed = false;
+            match &args.input_map {
+                Some(path) => match input_map.save(path) {
+                    Ok(_) => info!("Input map saved to {:?}", path),
+                    Err(e) => error!("{}", e),
+                },
+                None => info!("No --input-map given, new bindings last until we quit"),
+            }
+        }
+        return;
+    }
+
+    if !screen.visible {
+        return;
+    }
+
+    let last = Action::ALL.len() - 1;
+    if keys.just_pressed(KeyCode::ArrowUp) {
+        screen.selected = screen.selected.checked_sub(1).unwrap_or(last);
+    }
+    if keys.just_pressed(KeyCode::ArrowDown) {
+        screen.selected = if screen.selected == last {
+            0
+        } else {
+            screen.selected + 1
+        };
+    }
+    if keys.just_pressed(KeyCode::Enter) {
+        screen.waiting = true;
+    }
+    if keys.just_pressed(KeyCode::Backspace) {
+        *input_map.binding_mut(Action::ALL[screen.selected]) = Binding::default();
+        screen.changed = true;
+    }
+}
+
+pub fn update_rebind_screen(
+    screen: Res<RebindScreen>,
+    input_map: Res<InputMap>,
+    mut text: Query<&mut Text, With<RebindText>>,
+) {
+    if !screen.is_changed() && !input_map.is_changed() {
+        return;
+    }
+
+    let mut lines = String::from(
+        "Rebind controls (F1 to close)\nup/down: pick, enter: rebind, backspace: clear\n\n",
+    );
+    for (i, action) in Action::ALL.iter().enumerate() {
+        let cursor = if i == screen.selected { ">" } else { " " };
+        let binding = if i == screen.selected && screen.waiting {
+            "press a key or button...".to_owned()
+        } else {
+            input_map.binding(*action).to_string()
+        };
+        lines += &format!("{} {}: {}\n", cursor, action.name(), binding);
+    }
+
+    for mut text in text.iter_mut() {
+        text.sections[0].value.clone_from(&lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "bevy-ggrs-rapier-example-{}-{}.ron",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn input_maps_load_what_was_saved() {
+        let saved = InputMap {
+            jump: Binding::new(KeyCode::KeyJ, Some(GamepadButtonType::North)),
+            kick: Binding::default(),
+            ..default()
+        };
+
+        let path = temp_path("saved");
+        saved.save(&path).unwrap();
+        let loaded = InputMap::load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.unwrap(), saved);
+    }
+
+    #[test]
+    fn missing_bindings_keep_their_defaults() {
+        // Like a file saved before any of the other actions existed
+        let path = temp_path("partial");
+        std::fs::write(&path, "(jump: (keys: [KeyJ]))").unwrap();
+        let loaded = InputMap::load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        let expected = InputMap {
+            jump: Binding {
+                keys: vec![KeyCode::KeyJ],
+                buttons: Vec::new(),
+            },
+            ..default()
+        };
+        assert_eq!(loaded.unwrap(), expected);
+    }
+}
@@ -0,0 +1,9 @@
+//! The few parts of the game that stand on their own, without Bevy's app or
+//! our prelude, so the benches can use the real thing.  The game itself pulls
+//! these back in through its prelude.
+
+pub mod snapshots;
+
+// GGRS never rolls back further than this.  The snapshots need it to know how
+// many to keep.
+pub const MAX_PREDICTION: usize = 5;
@@ -1,10 +1,41 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Mutex;
+
 use crate::prelude::*;
-use tracing_log::LogTracer;
-use tracing_subscriber::{prelude::*, registry::Registry, EnvFilter};
+use bevy::utils::tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use bevy_ggrs::{LocalPlayers, RollbackFrameCount};
+use bevy_matchbox::{prelude::MultipleChannels, MatchboxSocket};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use tracing_log::{LogTracer, NormalizeEvent};
+use tracing_subscriber::{
+    fmt::{writer::BoxMakeWriter, MakeWriter},
+    layer::{Context, Layer},
+    prelude::*,
+    registry::Registry,
+    EnvFilter,
+};
 
 #[derive(Default)]
 pub struct LogPlugin;
 
+/// How each log line is written
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Just the message, for diffing
+    #[default]
+    Text,
+    /// One JSON object per line, with the frame, whether it is being
+    /// resimulated, and our peer id as fields of their own
+    Json,
+}
+
 /// `LogPlugin` settings
 #[derive(Resource)]
 pub struct LogSettings {
@@ -14,6 +45,12 @@ pub struct LogSettings {
     /// Filters out logs that are "less than" the given level.
     /// This can be further filtered using the `filter` setting.
     pub level: Level,
+
+    pub format: LogFormat,
+
+    /// When set, each instance logs to its own file in this directory instead
+    /// of stdout, named after its local player handles and peer id
+    pub output_dir: Option<PathBuf>,
 }
 
 impl Default for LogSettings {
@@ -21,18 +58,298 @@ impl Default for LogSettings {
         Self {
             filter: "wgpu=error".to_string(),
             level: Level::INFO,
+            format: LogFormat::default(),
+            output_dir: None,
         }
     }
 }
 
+/// No frame yet, e.g., while we wait for the other players
+const NO_FRAME: Frame = Frame::MIN;
+
+static FRAME: AtomicI32 = AtomicI32::new(NO_FRAME);
+static ROLLBACK: AtomicBool = AtomicBool::new(false);
+static PEER: Mutex<Option<String>> = Mutex::new(None);
+
+/// What we are doing right now, added to every JSON log line.  Logs can come
+/// from any thread, and the log layer can not see the world, so this lives in
+/// statics rather than a resource.
+pub struct LogContext;
+
+impl LogContext {
+    pub fn set_frame(frame: Frame) {
+        FRAME.store(frame, Ordering::Relaxed);
+    }
+
+    /// Between matches, there is no frame to speak of
+    pub fn clear_frame() {
+        FRAME.store(NO_FRAME, Ordering::Relaxed);
+        ROLLBACK.store(false, Ordering::Relaxed);
+    }
+
+    pub fn set_rollback(rollback: bool) {
+        ROLLBACK.store(rollback, Ordering::Relaxed);
+    }
+
+    pub fn set_peer(peer: Option<String>) {
+        *PEER.lock().unwrap() = peer;
+    }
+
+    fn frame() -> Option<Frame> {
+        Some(FRAME.load(Ordering::Relaxed)).filter(|f| *f != NO_FRAME)
+    }
+
+    fn rollback() -> bool {
+        ROLLBACK.load(Ordering::Relaxed)
+    }
+
+    fn peer() -> Option<String> {
+        PEER.lock().unwrap().clone()
+    }
+}
+
+/// How many bytes of log lines we hold on to before our session starts.  Any
+/// more, and they go to a file of their own instead, see [`PeerLogFile`].
+const PENDING_LIMIT: usize = 1 << 20;
+
+/// Where log lines go when we have an `output_dir`
+struct LogFile {
+    dir: PathBuf,
+    /// Lines from before we know who we are, and so what to name our file
+    pending: Vec<u8>,
+    file: Option<File>,
+    /// Sessions started so far.  Re-matches over UDP or in a sync test come
+    /// back with the same handles and no peer id, and start over at frame 0.
+    sessions: usize,
+}
+
+impl LogFile {
+    /// From now on, writes to `<name>-pid-<our pid>.log`, starting with
+    /// anything still pending.  The pid keeps instances sharing a directory
+    /// apart, even when they have the same handles and no peer id.
+    fn switch_to(&mut self, name: &str) {
+        let path = self
+            .dir
+            .join(format!("{}-pid-{}.log", name, std::process::id()));
+        let pending = std::mem::take(&mut self.pending);
+
+        match File::create(&path) {
+            Ok(mut file) => {
+                let _ = file.write_all(&pending);
+                self.file = Some(file);
+            }
+            Err(e) => eprintln!(
+                "Could not open log file {:?}, dropping {} bytes of log: {}",
+                path,
+                pending.len(),
+                e
+            ),
+        }
+    }
+}
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Writes log lines to this instance's own file, see
+/// [`LogSettings::output_dir`].  Until our session starts we do not know our
+/// handles or peer id, so lines are held on to until then.  Should that take
+/// long enough for [`PENDING_LIMIT`] to be reached, say while waiting in a
+/// matchbox room, they go to `before-session-pid-<pid>.log` instead.
+pub struct PeerLogFile;
+
+impl PeerLogFile {
+    fn init(dir: PathBuf) {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            eprintln!("Could not create log directory {:?}: {}", dir, e);
+        }
+        *LOG_FILE.lock().unwrap() = Some(LogFile {
+            dir,
+            pending: Vec::new(),
+            file: None,
+            sessions: 0,
+        });
+    }
+
+    /// Starts a new file for a new session.  Each one is numbered, so every
+    /// match ends up in a file of its own, whichever way we connect.
+    fn open(handles: &[usize], peer: Option<&str>) {
+        let mut log_file = LOG_FILE.lock().unwrap();
+        let Some(log_file) = log_file.as_mut() else {
+            return;
+        };
+
+        let handles = handles
+            .iter()
+            .map(|h| h.to_string())
+            .collect::<Vec<_>>()
+            .join("-");
+        log_file.sessions += 1;
+        let name = format!(
+            "handle-{}-peer-{}-session-{}",
+            if handles.is_empty() { "none" } else { &handles },
+            peer.unwrap_or("local"),
+            log_file.sessions
+        );
+        log_file.switch_to(&name);
+    }
+}
+
+impl Write for PeerLogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(LogFile {
+                file: Some(file), ..
+            }) => file.write(buf),
+            Some(log_file) => {
+                log_file.pending.extend_from_slice(buf);
+                if log_file.pending.len() > PENDING_LIMIT {
+                    log_file.switch_to("before-session");
+                }
+                Ok(buf.len())
+            }
+            None => std::io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(LogFile {
+                file: Some(file), ..
+            }) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes every event as a line of JSON, like
+/// `{"level":"INFO","target":"...","frame":12,"rollback":false,"peer":"...","message":"..."}`
+pub struct JsonLayer<W> {
+    make_writer: W,
+}
+
+impl<W> JsonLayer<W> {
+    pub fn new(make_writer: W) -> Self {
+        Self { make_writer }
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        // Events from the `log` crate all look like they came from `log`,
+        // unless we ask where they really came from
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut line = Map::new();
+        line.insert("level".into(), metadata.level().as_str().into());
+        line.insert("target".into(), metadata.target().into());
+        line.insert("frame".into(), LogContext::frame().into());
+        line.insert("rollback".into(), LogContext::rollback().into());
+        line.insert("peer".into(), LogContext::peer().into());
+        event.record(&mut JsonVisitor(&mut line));
+
+        let mut writer = self.make_writer.make_writer();
+        let _ = writeln!(writer, "{}", Value::Object(line));
+    }
+}
+
+/// Adds an event's fields to its JSON line
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // These are only there to normalize `log` events, see above
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().into(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
+
+/// Keeps the frame in [`LogContext`] up to date.  Runs first thing in the
+/// frame, so even the "start frame" line has the right one.
+pub fn update_log_frame(current_frame: Res<RollbackFrameCount>) {
+    LogContext::set_frame((*current_frame).into());
+}
+
+/// The last match's frame is over as soon as the match is.  Lines logged
+/// while we wait for the next one should not look like they belong to it.
+pub fn reset_log_context() {
+    LogContext::clear_frame();
+}
+
+/// Runs as soon as we know whether this frame is being resimulated, and before
+/// the frame logs anything, so even its first line is marked
+pub fn update_log_rollback(rollback_status: Res<RollbackStatus>) {
+    LogContext::set_rollback(rollback_status.is_replay);
+}
+
+/// Tells [`LogContext`] and [`PeerLogFile`] who we are whenever a new session
+/// gives us our local players
+pub fn update_log_identity(
+    local_players: Res<LocalPlayers>,
+    socket: Option<ResMut<MatchboxSocket<MultipleChannels>>>,
+) {
+    let peer = socket.and_then(|mut s| s.id()).map(|id| id.to_string());
+    PeerLogFile::open(&local_players.0, peer.as_deref());
+    LogContext::set_peer(peer);
+}
+
 impl Plugin for LogPlugin {
     fn build(&self, app: &mut App) {
-        let default_filter = {
+        let (default_filter, format, output_dir) = {
             let settings = app
                 .world_mut()
                 .get_resource_or_insert_with(LogSettings::default);
-            format!("{},{}", settings.level, settings.filter)
+            (
+                format!("{},{}", settings.level, settings.filter),
+                settings.format,
+                settings.output_dir.clone(),
+            )
         };
+
+        // Both layers write wherever we were told to, stdout by default
+        let to_file = output_dir.is_some();
+        if let Some(dir) = output_dir {
+            PeerLogFile::init(dir);
+        }
+        let make_writer = || {
+            if to_file {
+                BoxMakeWriter::new(|| PeerLogFile)
+            } else {
+                BoxMakeWriter::new(std::io::stdout)
+            }
+        };
+
         LogTracer::init().unwrap();
         let filter_layer = EnvFilter::try_from_default_env()
             .or_else(|_| EnvFilter::try_new(&default_filter))
@@ -41,13 +358,19 @@ impl Plugin for LogPlugin {
 
         // Allow us to output our logging for quick diffing.
         // e.g., `cargo run > log1.log` and `cargo run > log2.log`
-        let fmt_layer = tracing_subscriber::fmt::Layer::default()
-            .without_time()
-            .with_target(false)
-            .with_level(false)
-            .with_ansi(false);
+        let fmt_layer = (format == LogFormat::Text).then(|| {
+            tracing_subscriber::fmt::Layer::default()
+                .without_time()
+                .with_target(false)
+                .with_level(false)
+                .with_ansi(false)
+                .with_writer(make_writer())
+        });
+
+        // Or for our tooling to read, without picking apart the messages
+        let json_layer = (format == LogFormat::Json).then(|| JsonLayer::new(make_writer()));
 
-        let subscriber = subscriber.with(fmt_layer);
+        let subscriber = subscriber.with(fmt_layer).with(json_layer);
 
         bevy::utils::tracing::subscriber::set_global_default(subscriber)
                 .expect("Could not set global default tracing subscriber. If you've already set up a tracing subscriber, please disable LogPlugin from Bevy's DefaultPlugins");
@@ -1,37 +1,69 @@
+mod abilities;
+mod args;
+mod body_checksums;
 mod colliders;
+mod desync;
+mod dynamic_spawns;
 mod frames;
+#[cfg(test)]
+mod harness;
+mod input_map;
 mod log_plugin;
+mod metrics;
 mod network;
+mod network_stats;
 mod physics;
 mod random_movement;
+mod replay;
 mod rollback;
 mod spawn;
+mod spectator;
 mod startup;
+#[cfg(not(target_arch = "wasm32"))]
+mod udp;
 
 // A prelude to simplify other file imports
 mod prelude {
+    pub use crate::abilities::*;
+    pub use crate::args::*;
+    pub use crate::body_checksums::*;
     pub use crate::colliders::*;
+    pub use crate::desync::*;
+    pub use crate::dynamic_spawns::*;
     pub use crate::frames::*;
-    pub use crate::log_plugin::LogSettings;
+    pub use crate::input_map::*;
+    pub use crate::log_plugin::{
+        reset_log_context, update_log_frame, update_log_identity, update_log_rollback, LogContext,
+        LogFormat, LogSettings,
+    };
+    pub use crate::metrics::*;
     pub use crate::network::*;
+    pub use crate::network_stats::*;
     pub use crate::physics::*;
     pub use crate::random_movement::*;
+    pub use crate::replay::*;
     pub use crate::rollback::*;
     pub use crate::spawn::*;
+    pub use crate::spectator::*;
     pub use crate::startup::*;
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use crate::udp::*;
     pub use bevy::log::*;
     pub use bevy::prelude::*;
     pub use bevy_framepace::{FramepacePlugin, FramepaceSettings, Limiter};
     pub use bevy_ggrs::prelude::*;
+    pub use bevy_ggrs_rapier_example::snapshots::*;
     pub use bevy_inspector_egui::quick::WorldInspectorPlugin;
     pub use bevy_rapier2d::prelude::*;
     pub use bytemuck::{Pod, Zeroable};
     pub use ggrs::{Frame, InputStatus, PlayerType, SessionBuilder};
-    pub use rand::{thread_rng, Rng};
+    pub use rand::Rng;
 
-    pub const NUM_PLAYERS: usize = 2;
+    // The player count itself is picked at runtime with `--players`
+    pub const MAX_PLAYERS: usize = 4;
     pub const FPS: usize = 60;
-    pub const MAX_PREDICTION: usize = 5;
+    // Kept in the library next to the snapshots, which size themselves by it
+    pub use bevy_ggrs_rapier_example::MAX_PREDICTION;
     pub const INPUT_DELAY: usize = 3;
 
     // Having a "load screen" time helps with initial desync issues.  No idea why,
@@ -47,17 +79,18 @@ mod prelude {
     // They host this match making service for us to use FOR FREE.
     // It has been an incredibly useful thing I don't have to think about while working
     // and learning how to implement this stuff and I guarantee it will be for you too.
-    // pub const MATCHBOX_ADDR: &str = "wss://match.gschup.dev/bevy-ggrs-rapier-example?next=2";
+    // pub const MATCHBOX_SERVER: &str = "wss://match.gschup.dev";
     // Unfortunately, this matchbox is too out of date to work with the latest plugin.
 
     // So, use Johan's compatible matchbox.
     // Check out their work on "Cargo Space", especially the blog posts, which are incredibly enlightening!
     // https://johanhelsing.studio/cargospace
-    pub const MATCHBOX_ADDR: &str =
-        "wss://match-0-7.helsing.studio/bevy-ggrs-rapier-example?next=2";
-    // Care to run your own matchbox?  Great!
-    // pub const MATCHBOX_ADDR: &str = "ws://localhost:3536/bevy-ggrs-rapier-example?next=2";
-    // TODO: Maybe update this room name (bevy-ggrs-rapier-example) so we don't test with each other :-)
+    pub const MATCHBOX_SERVER: &str = "wss://match-0-7.helsing.studio";
+    // Care to run your own matchbox?  Great!  Pass `--matchbox ws://localhost:3536`
+
+    // These are only defaults.  Pick your own room with `--room` so we don't
+    // test with each other :-)
+    pub const MATCHBOX_ROOM: &str = "bevy-ggrs-rapier-example";
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
@@ -67,14 +100,129 @@ enum ExampleSystemSets {
     SaveAndChecksum,
 }
 
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::asset::AssetPlugin;
 use bevy::ecs::schedule::ScheduleBuildSettings;
+use bevy::input::InputPlugin;
+use bevy::scene::ScenePlugin;
+use bevy::utils::Duration;
 use bevy_ggrs::{GgrsApp, GgrsPlugin};
+use bevy_matchbox::{prelude::MultipleChannels, MatchboxSocket};
 
 use crate::prelude::*;
 
-fn main() {
+fn main() -> AppExit {
+    let mut args = Args::from_env();
+
+    // A replay brings its own settings, so read it before anything uses ours
+    let playback = (args.mode == RunMode::Replay).then(|| ReplayPlayback::load(&mut args));
+
     let mut app = App::new();
 
+    spawn_deterministic_pool(&mut app);
+
+    app.insert_resource(ClearColor(Color::BLACK))
+        .insert_resource(LogSettings {
+            level: Level::INFO,
+            format: args.log_format,
+            output_dir: args.log_dir.clone(),
+            ..default()
+        });
+
+    if args.headless {
+        // Replaying as fast as we can, there is no reason to wait between
+        // updates
+        let wait = if playback.is_some() && args.replay_speed == ReplaySpeed::Max {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(1. / FPS as f64)
+        };
+        add_headless_plugins(&mut app, wait);
+    } else {
+        // Something smaller so we can put these side by side
+        let window_info = Window {
+            title: "Example".into(),
+            resolution: (800.0, 600.0).into(),
+            ..default()
+        };
+
+        // DefaultPlugins will use window descriptor
+        app.add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: Some(window_info),
+                    ..default()
+                })
+                .build()
+                .disable::<LogPlugin>(),
+        )
+        .add_systems(Startup, spawn_camera)
+        .add_systems(Update, close_on_esc)
+        .init_resource::<NetworkStatsOverlay>()
+        .add_systems(Startup, spawn_network_stats)
+        .add_systems(Update, (toggle_network_stats, update_network_stats).chain())
+        .init_resource::<RebindScreen>()
+        .add_systems(Startup, spawn_rebind_screen)
+        .add_systems(Update, (rebind_input, update_rebind_screen).chain());
+    }
+
+    app.insert_resource(args.clone())
+        // Add our own log plugin to help with comparing desync output
+        .add_plugins(log_plugin::LogPlugin)
+        .add_systems(Startup, (load_input_map, connect))
+        .add_systems(Update, toggle_random_input)
+        .add_systems(
+            Update,
+            update_matchbox_socket.run_if(resource_exists::<MatchboxSocket<MultipleChannels>>),
+        )
+        // Every new session gives us new local players, and maybe a new peer id
+        .add_systems(
+            Update,
+            update_log_identity.run_if(resource_exists_and_changed::<bevy_ggrs::LocalPlayers>),
+        )
+        .add_systems(Update, (handle_p2p_events, write_desync_report).chain())
+        .add_event::<DesyncEvent>()
+        .add_systems(Update, update_rollback_metrics_rates)
+        .add_systems(Last, (write_rollback_metrics_on_exit, write_replay_on_exit));
+
+    // Back to matchmaking, or the same UDP peers, for a new match
+    add_peer_left_reset(&mut app, connect);
+
+    if args.mode == RunMode::Spectate {
+        app.add_systems(Startup, spawn_spectator_hud)
+            .add_systems(Update, update_spectator_hud);
+    }
+
+    add_simulation(&mut app);
+
+    if let Some(playback) = playback {
+        add_replay_playback(&mut app, playback, args.replay_speed);
+    }
+
+    if !args.headless {
+        // We don't really draw anything ourselves, just show us the raw physics colliders
+        app.add_plugins(RapierDebugRenderPlugin {
+            enabled: true,
+            ..default()
+        })
+        .add_plugins(WorldInspectorPlugin::new());
+
+        // I have found that since GGRS is limiting the movement FPS anyway,
+        // there isn't much of a point in rendering more frames than necessary.
+        // One thing I've yet to prove out is if this is actually detrimental or
+        // not to resimulation, since we're basically taking up time that GGRS
+        // would use already to pace itself.
+        // You may find this useless, or bad.  Submit a PR if it is!
+        app.insert_resource(FramepaceSettings {
+            limiter: Limiter::from_framerate(FPS as f64),
+        })
+        .add_plugins(FramepacePlugin);
+    }
+
+    app.run()
+}
+
+fn spawn_deterministic_pool(app: &mut App) {
     // First thing's first:  we need to gain control of how our entities that
     // will have physics interactions spawn.  This generates placeholders at
     // the very start, ensuring the first thing this app does is have a pool
@@ -92,54 +240,89 @@ fn main() {
         .world_mut()
         .spawn_batch((0..101).map(DeterministicSpawnBundle::new))
         .collect::<Vec<Entity>>();
+}
 
-    // Something smaller so we can put these side by side
-    let window_info = Window {
-        title: "Example".into(),
-        resolution: (800.0, 600.0).into(),
-        ..default()
-    };
-
-    // DefaultPlugins will use window descriptor
-    app.insert_resource(ClearColor(Color::BLACK))
-        .insert_resource(LogSettings {
-            level: Level::INFO,
-            ..default()
-        })
-        .add_plugins(
-            DefaultPlugins
-                .set(WindowPlugin {
-                    primary_window: Some(window_info),
-                    ..default()
-                })
-                .build()
-                .disable::<LogPlugin>(),
+/// When our peer leaves, put the world back the way it was at startup and
+/// start a new match with `reconnect`.  The notice stays up until
+/// `handle_p2p_events` sees us synchronize with whoever we play next.
+fn add_peer_left_reset<M>(app: &mut App, reconnect: impl IntoSystemConfigs<M>) {
+    app.add_systems(
+        Update,
+        (
+            (
+                write_replay_on_peer_left,
+                reset_log_context,
+                reset_frame_counts,
+                startup,
+                reset_rapier,
+                respawn_all,
+                reconnect,
+                show_peer_left,
+            )
+                .chain()
+                .run_if(resource_added::<PeerLeft>),
+            hide_peer_left.run_if(resource_removed::<PeerLeft>()),
         )
-        // Add our own log plugin to help with comparing desync output
-        .add_plugins(log_plugin::LogPlugin)
-        .add_systems(Startup, startup)
+            .chain()
+            .after(handle_p2p_events),
+    );
+}
+
+/// No window, no renderer.  Just enough of Bevy to run the GGRS schedule and
+/// Rapier, so this can run on machines without a GPU.
+fn add_headless_plugins(app: &mut App, wait: Duration) {
+    app.add_plugins((
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(wait)),
+        TransformPlugin,
+        HierarchyPlugin,
+        // Our input system still reads the keyboard and gamepads, nobody is
+        // pressing them
+        InputPlugin,
+        // bevy_rapier's async collider systems want meshes and scenes
+        AssetPlugin::default(),
+        ScenePlugin,
+    ))
+    .init_asset::<Mesh>();
+}
+
+/// Everything that makes up the simulation itself: the startup systems, GGRS,
+/// our rollback schedule and Rapier.  How the session is created, and how (or
+/// if) it is drawn, is up to the caller.
+fn add_simulation(app: &mut App) {
+    app.add_systems(Startup, startup)
         .add_systems(Startup, reset_rapier)
         .add_systems(Startup, respawn_all)
-        .add_systems(Startup, connect)
-        .add_systems(Update, toggle_random_input)
-        .add_systems(Update, close_on_esc)
-        .add_systems(Update, update_matchbox_socket)
-        .add_systems(Update, handle_p2p_events);
+        .init_resource::<RollbackMetrics>()
+        .register_type::<RollbackMetrics>()
+        // Until `load_input_map` says otherwise
+        .init_resource::<InputMap>();
 
     app.add_plugins(GgrsPlugin::<ExampleGgrsConfig>::default())
         .set_rollback_schedule_fps(FPS)
-        .add_systems(bevy_ggrs::ReadInputs, input)
+        .add_systems(
+            bevy_ggrs::ReadInputs,
+            (
+                input.run_if(not(resource_exists::<ReplayPlayback>)),
+                replay_input.run_if(resource_exists::<ReplayPlayback>),
+            ),
+        )
         // We must add a specific checksum check for everything we want to include in desync detection.
         // It is probably OK to just check the components, but for demo purposes let's make sure Rapier always agrees.
         .checksum_resource_with_hash::<PhysicsRollbackState>()
-        .rollback_resource_with_clone::<PhysicsRollbackState>()
+        .rollback_resource_with_copy::<PhysicsRollbackState>()
         // Store everything that Rapier updates in its Writeback stage
         .rollback_component_with_reflect::<GlobalTransform>()
         .rollback_component_with_reflect::<Transform>()
         .rollback_component_with_reflect::<Velocity>()
         .rollback_component_with_reflect::<Sleeping>()
         // Game stuff
-        .rollback_resource_with_reflect::<EnablePhysicsAfter>();
+        .rollback_resource_with_reflect::<EnablePhysicsAfter>()
+        .rollback_component_with_reflect::<AbilityCooldowns>()
+        .checksum_component_with_hash::<AbilityCooldowns>()
+        .checksum_resource_with_hash::<DynamicSpawns>()
+        .rollback_resource_with_clone::<DynamicSpawns>()
+        .checksum_resource_with_hash::<RollbackRng>()
+        .rollback_resource_with_copy::<RollbackRng>();
 
     // We need to a bunch of systems into the GGRSSchedule.
     // So, grab it and lets configure it with our systems, and the one from Rapier.
@@ -169,14 +352,24 @@ fn main() {
         )
         .add_systems(
             (
-                log_start_frame,
+                update_log_frame,
                 update_current_session_frame,
-                log_confirmed_frame,
-                // the three above must actually come before we update rollback status
+                // the two above must actually come before we update rollback status
                 update_rollback_status,
-                // these three must actually come after we update rollback status
+                // everything below must actually come after we update rollback
+                // status, and nothing above logs, so every line of the frame
+                // knows whether it is a resimulation
+                update_log_rollback,
+                log_start_frame,
+                log_frame_status,
+                log_confirmed_frame,
+                record_rollback_history,
+                record_rollback_metrics,
                 toggle_physics,
                 rollback_rapier_context,
+                // Puts back (or takes away) bodies spawned since, now that
+                // their Rapier bodies are back (or gone)
+                sync_dynamic_spawns,
                 // Make sure to flush everything before we apply our game logic.
                 apply_deferred,
             )
@@ -189,7 +382,11 @@ fn main() {
         )
         .add_systems(
             (
+                apply_abilities,
                 apply_inputs,
+                despawn_expired_bodies,
+                spawn_extra_balls.run_if(extra_balls_enabled),
+                sync_dynamic_spawns,
                 force_update_rollbackables,
                 // Make sure to flush everything before Rapier syncs
                 apply_deferred,
@@ -212,6 +409,9 @@ fn main() {
         .add_systems(
             (
                 save_rapier_context, // This must execute after writeback to store the RapierContext
+                record_body_checksums.run_if(body_checksums_enabled),
+                record_replay_frame.run_if(recording_replays),
+                verify_replay_frame.run_if(resource_exists::<ReplayPlayback>),
                 pause_physics_test,
                 log_end_frame,
                 apply_deferred, // Flushing again
@@ -249,26 +449,6 @@ fn main() {
     rapier_config.force_update_from_transform_changes = true;
 
     app.insert_resource(rapier_config);
-
-    // We don't really draw anything ourselves, just show us the raw physics colliders
-    app.add_plugins(RapierDebugRenderPlugin {
-        enabled: true,
-        ..default()
-    })
-    .add_plugins(WorldInspectorPlugin::new());
-
-    // I have found that since GGRS is limiting the movement FPS anyway,
-    // there isn't much of a point in rendering more frames than necessary.
-    // One thing I've yet to prove out is if this is actually detrimental or
-    // not to resimulation, since we're basically taking up time that GGRS
-    // would use already to pace itself.
-    // You may find this useless, or bad.  Submit a PR if it is!
-    app.insert_resource(FramepaceSettings {
-        limiter: Limiter::from_framerate(FPS as f64),
-    })
-    .add_plugins(FramepacePlugin);
-
-    app.run();
 }
 
 pub fn close_on_esc(
@@ -0,0 +1,158 @@
+use std::fmt::Write;
+
+use bevy::app::AppExit;
+use bevy::utils::{Duration, Instant};
+
+use crate::prelude::*;
+
+/// Rollbacks this deep or deeper all land in the last histogram bucket
+pub const ROLLBACK_DEPTH_BUCKETS: usize = MAX_PREDICTION + 2;
+
+/// Everything we know about how much rolling back we have been doing.  Like
+/// [`RollbackStatus`], this is left outside of the rollback system.  Shows up
+/// in the inspector, and is written out with `--metrics-csv` when we exit.
+#[derive(Resource, Reflect, Debug, Clone)]
+#[reflect(Resource)]
+pub struct RollbackMetrics {
+    /// Every frame we simulated, including resimulated ones
+    pub frames: u64,
+    pub rollbacks: u64,
+    pub resimulated_frames: u64,
+    /// How many rollbacks resimulated `n` frames, at index `n`
+    pub rollback_depths: Vec<u64>,
+    /// Rollbacks and resimulated frames over the last second
+    pub rollbacks_per_second: u64,
+    pub resimulated_frames_per_second: u64,
+    pub saves: u64,
+    pub save_time: Duration,
+    pub restores: u64,
+    pub restore_time: Duration,
+
+    #[reflect(ignore)]
+    pub last_second: Option<Instant>,
+    #[reflect(ignore)]
+    pub rollbacks_at_last_second: u64,
+    #[reflect(ignore)]
+    pub resimulated_frames_at_last_second: u64,
+}
+
+impl Default for RollbackMetrics {
+    fn default() -> Self {
+        Self {
+            frames: 0,
+            rollbacks: 0,
+            resimulated_frames: 0,
+            rollback_depths: vec![0; ROLLBACK_DEPTH_BUCKETS],
+            rollbacks_per_second: 0,
+            resimulated_frames_per_second: 0,
+            saves: 0,
+            save_time: Duration::ZERO,
+            restores: 0,
+            restore_time: Duration::ZERO,
+            last_second: None,
+            rollbacks_at_last_second: 0,
+            resimulated_frames_at_last_second: 0,
+        }
+    }
+}
+
+impl RollbackMetrics {
+    pub fn record_save(&mut self, elapsed: Duration) {
+        self.saves += 1;
+        self.save_time += elapsed;
+    }
+
+    pub fn record_restore(&mut self, elapsed: Duration) {
+        self.restores += 1;
+        self.restore_time += elapsed;
+    }
+
+    fn mean(total: Duration, count: u64) -> Duration {
+        if count == 0 {
+            Duration::ZERO
+        } else {
+            total / count as u32
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("metric,value\n");
+        let _ = writeln!(csv, "frames,{}", self.frames);
+        let _ = writeln!(csv, "rollbacks,{}", self.rollbacks);
+        let _ = writeln!(csv, "resimulated_frames,{}", self.resimulated_frames);
+        let _ = writeln!(csv, "saves,{}", self.saves);
+        let _ = writeln!(csv, "save_time_us,{}", self.save_time.as_micros());
+        let _ = writeln!(
+            csv,
+            "mean_save_time_us,{}",
+            Self::mean(self.save_time, self.saves).as_micros()
+        );
+        let _ = writeln!(csv, "restores,{}", self.restores);
+        let _ = writeln!(csv, "restore_time_us,{}", self.restore_time.as_micros());
+        let _ = writeln!(
+            csv,
+            "mean_restore_time_us,{}",
+            Self::mean(self.restore_time, self.restores).as_micros()
+        );
+        for (depth, count) in self.rollback_depths.iter().enumerate() {
+            let _ = writeln!(csv, "rollback_depth_{},{}", depth, count);
+        }
+        csv
+    }
+}
+
+pub fn record_rollback_metrics(
+    rollback_status: Res<RollbackStatus>,
+    mut metrics: ResMut<RollbackMetrics>,
+) {
+    metrics.frames += 1;
+
+    if rollback_status.is_rollback {
+        metrics.rollbacks += 1;
+        let bucket =
+            (rollback_status.rollback_depth.max(0) as usize).min(ROLLBACK_DEPTH_BUCKETS - 1);
+        metrics.rollback_depths[bucket] += 1;
+    }
+
+    if rollback_status.is_replay {
+        metrics.resimulated_frames += 1;
+    }
+}
+
+/// Rates are measured against the wall clock, so this runs outside of the
+/// rollback schedule
+pub fn update_rollback_metrics_rates(mut metrics: ResMut<RollbackMetrics>) {
+    let now = Instant::now();
+    let Some(last_second) = metrics.last_second else {
+        metrics.last_second = Some(now);
+        return;
+    };
+
+    if now.duration_since(last_second) >= Duration::from_secs(1) {
+        metrics.rollbacks_per_second = metrics.rollbacks - metrics.rollbacks_at_last_second;
+        metrics.rollbacks_at_last_second = metrics.rollbacks;
+        metrics.resimulated_frames_per_second =
+            metrics.resimulated_frames - metrics.resimulated_frames_at_last_second;
+        metrics.resimulated_frames_at_last_second = metrics.resimulated_frames;
+        metrics.last_second = Some(now);
+    }
+}
+
+pub fn write_rollback_metrics_on_exit(
+    mut exits: EventReader<AppExit>,
+    metrics: Res<RollbackMetrics>,
+    args: Res<Args>,
+) {
+    if exits.read().next().is_none() {
+        return;
+    }
+
+    let Some(path) = &args.metrics_csv else {
+        return;
+    };
+
+    match std::fs::write(path, metrics.to_csv()) {
+        Ok(_) => info!("Rollback metrics written to {:?}", path),
+        Err(e) => error!("Could not write rollback metrics to {:?}: {}", path, e),
+    }
+}
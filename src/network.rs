@@ -1,21 +1,138 @@
 use bevy_ggrs::LocalPlayers;
 use bevy_matchbox::{
-    prelude::{PeerState, SingleChannel},
+    prelude::{ChannelConfig, MultipleChannels, PeerId, PeerState, WebRtcSocketBuilder},
     MatchboxSocket,
 };
 
 use crate::prelude::*;
 
-pub fn connect(mut commands: Commands) {
+/// Inserted when our remote peer leaves the match.  The surviving player sees
+/// a notice while we start a new session, and it is removed once that session
+/// has synchronized with someone.  Matchbox, UDP and spectator sessions all go
+/// through the same [`GgrsEvent::Synchronized`], so none of them has to clean
+/// this up itself.
+#[derive(Resource, Debug)]
+pub struct PeerLeft {
+    pub peer: PeerId,
+}
+
+/// Marker for the "peer left" notice, so we can clean it up later
+#[derive(Component)]
+pub struct PeerLeftText;
+
+/// GGRS has the first matchbox channel to itself.  On this second, reliable,
+/// one, everyone tells everyone else whether they are a player or a spectator,
+/// as the matchbox itself has no idea.
+const ROLE_CHANNEL: usize = 1;
+const ROLE_PLAYER: u8 = 0;
+const ROLE_SPECTATOR: u8 = 1;
+
+/// Who else is in our matchbox room, by what they told us on the
+/// [`ROLE_CHANNEL`].  Starts over with every new socket.
+#[derive(Resource, Default, Debug)]
+pub struct MatchboxRoles {
+    pub players: Vec<PeerId>,
+    pub spectators: Vec<PeerId>,
+}
+
+pub fn connect(mut commands: Commands, args: Res<Args>) {
+    match args.mode {
+        RunMode::Matchbox => connect_matchbox(&mut commands, &args),
+        RunMode::SyncTest => {
+            start_synctest_session(&mut commands, args.players, args.check_distance)
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        RunMode::Udp => start_udp_session(
+            &mut commands,
+            args.players,
+            args.udp_port,
+            &args.udp_players,
+            &args.udp_spectators,
+        ),
+        RunMode::Spectate => match args.udp_host.as_deref() {
+            #[cfg(not(target_arch = "wasm32"))]
+            Some(host) => start_spectator_session(
+                &mut commands,
+                args.players,
+                args.udp_port,
+                host,
+                args.max_frames_behind,
+                args.catchup_speed,
+            ),
+            _ => connect_matchbox(&mut commands, &args),
+        },
+        // Nobody to connect to, just a local session for the replay's
+        // inputs to go through.  Every frame is simulated once, straight
+        // through, as the checksums are checked against the replay instead.
+        #[cfg(not(target_arch = "wasm32"))]
+        RunMode::Replay => {
+            info!("Playing back {:?}", args.replay);
+            start_synctest_session(&mut commands, args.players, 0)
+        }
+        #[cfg(target_arch = "wasm32")]
+        RunMode::Udp => {
+            panic!("Direct UDP sessions are not available on the web")
+        }
+        #[cfg(target_arch = "wasm32")]
+        RunMode::Replay => {
+            panic!("There are no replay files to play back on the web")
+        }
+    }
+}
+
+fn connect_matchbox(commands: &mut Commands, args: &Args) {
     // Connect immediately.
-    // This starts to poll the matchmaking service for our other player to connect.
-    commands.insert_resource(MatchboxSocket::new_ggrs(MATCHBOX_ADDR));
+    // This starts to poll the matchmaking service for everyone else to connect.
+    let url = args.matchbox_url();
+    info!("Connecting to matchbox at {}", url);
+    let socket = WebRtcSocketBuilder::new(url)
+        .add_channel(ChannelConfig::unreliable())
+        .add_channel(ChannelConfig::reliable());
+    commands.insert_resource(MatchboxSocket::from(socket));
+    commands.insert_resource(MatchboxRoles::default());
+}
+
+/// The session settings shared by every kind of session we start
+pub fn session_builder(num_players: usize) -> SessionBuilder<ExampleGgrsConfig> {
+    SessionBuilder::<ExampleGgrsConfig>::new()
+        .with_num_players(num_players)
+        .with_max_prediction_window(MAX_PREDICTION)
+        .expect("Invalid prediction window")
+        .with_fps(FPS)
+        .expect("Invalid FPS")
+        .with_input_delay(INPUT_DELAY)
+        // Sparse saving should be off since we are serializing every frame
+        // anyway.  With it on, it seems that there are going to be more frames
+        // in between rollbacks and that can lead to more inaccuracies building
+        // up over time.
+        .with_sparse_saving_mode(false)
+}
+
+fn start_synctest_session(commands: &mut Commands, num_players: usize, check_distance: usize) {
+    // A SyncTest session has no remote players.  Every player is local, and
+    // GGRS rolls back `check_distance` frames on every frame, comparing the
+    // checksums of the resimulated frames with the ones it saw the first time.
+    let mut session_build = session_builder(num_players).with_check_distance(check_distance);
+    for i in 0..num_players {
+        session_build = session_build
+            .add_player(PlayerType::Local, i)
+            .expect("Invalid player added.");
+    }
+
+    let session = session_build
+        .start_synctest_session()
+        .expect("Session could not be created.");
+
+    commands.insert_resource(LocalPlayers((0..num_players).collect()));
+    commands.insert_resource(Session::SyncTest(session));
 }
 
 pub fn update_matchbox_socket(
     mut commands: Commands,
-    mut socket: ResMut<MatchboxSocket<SingleChannel>>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut roles: ResMut<MatchboxRoles>,
     session: Option<Res<Session<ExampleGgrsConfig>>>,
+    args: Res<Args>,
 ) {
     if session.is_some() {
         // Already have a session, skip for now.
@@ -24,49 +141,118 @@ pub fn update_matchbox_socket(
         return;
     }
 
+    let spectating = args.mode == RunMode::Spectate;
+
     // regularly call update_peers to update the list of connected peers
     for (peer, new_state) in socket.update_peers() {
         // you can also handle the specific dis(connections) as they occur:
         match new_state {
-            PeerState::Connected => info!("peer {peer:?} connected"),
-            PeerState::Disconnected => info!("peer {peer:?} disconnected"),
+            PeerState::Connected => {
+                info!("peer {peer:?} connected");
+                let role = if spectating {
+                    ROLE_SPECTATOR
+                } else {
+                    ROLE_PLAYER
+                };
+                socket
+                    .channel_mut(ROLE_CHANNEL)
+                    .send(vec![role].into_boxed_slice(), peer);
+            }
+            PeerState::Disconnected => {
+                info!("peer {peer:?} disconnected");
+                roles.players.retain(|p| *p != peer);
+                roles.spectators.retain(|p| *p != peer);
+            }
+        }
+    }
+
+    for (peer, packet) in socket.channel_mut(ROLE_CHANNEL).receive() {
+        match packet.first() {
+            Some(&ROLE_PLAYER) => roles.players.push(peer),
+            Some(&ROLE_SPECTATOR) => roles.spectators.push(peer),
+            _ => warn!("peer {peer:?} sent an unknown role {:?}", packet),
         }
     }
 
-    // Need one peer
-    if socket.connected_peers().count() == 0 {
+    // Need everyone else in the room, and to know what each of them is
+    let others = args.players + args.spectators - 1;
+    if roles.players.len() + roles.spectators.len() < others {
+        return;
+    }
+    let Some(id) = socket.id() else {
+        return;
+    };
+
+    // Everyone sorts the players the same way, so we all agree on the handles,
+    // and on who hosts the spectators
+    let mut players = roles.players.clone();
+    if !spectating {
+        players.push(id);
+    }
+    players.sort();
+    let mut spectators = roles.spectators.clone();
+    spectators.sort();
+
+    if players.len() != args.players {
+        // We look again every update, but only need to say so once per
+        // change in the room
+        if !roles.is_changed() {
+            return;
+        }
+        error!(
+            "The room has {} players and {} spectators, expected {} and {}.  \
+            Is everyone using the same --players and --spectators?",
+            players.len(),
+            spectators.len() + spectating as usize,
+            args.players,
+            args.spectators
+        );
+        return;
+    }
+    let host = players[0];
+    let channel = socket.take_channel(0).unwrap();
+
+    if spectating {
+        let session =
+            spectator_session_builder(args.players, args.max_frames_behind, args.catchup_speed)
+                .start_spectator_session(host, channel);
+        info!("Spectating the match hosted by {:?}", host);
+
+        // We do not play, so we have no inputs to give
+        commands.insert_resource(LocalPlayers(Vec::new()));
+        commands.insert_resource(Session::Spectator(session));
         return;
     }
 
     // create a new ggrs session
-    let mut session_build = SessionBuilder::<ExampleGgrsConfig>::new()
-        .with_num_players(NUM_PLAYERS)
-        .with_max_prediction_window(MAX_PREDICTION)
-        .expect("Invalid prediction window")
-        .with_fps(FPS)
-        .expect("Invalid FPS")
-        .with_input_delay(INPUT_DELAY)
-        // Sparse saving should be off since we are serializing every frame
-        // anyway.  With it on, it seems that there are going to be more frames
-        // in between rollbacks and that can lead to more inaccuracies building
-        // up over time.
-        .with_sparse_saving_mode(false)
+    let mut session_build = session_builder(args.players)
         .with_desync_detection_mode(ggrs::DesyncDetection::On { interval: 1 });
 
     // add players
-    let players = socket.players();
     let mut handles = Vec::new();
     for (i, player) in players.into_iter().enumerate() {
-        if player == PlayerType::Local {
+        let player = if player == id {
             handles.push(i);
-        }
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(player)
+        };
         session_build = session_build
             .add_player(player, i)
             .expect("Invalid player added.");
     }
 
+    // GGRS spectators follow a single host, which sends them our confirmed
+    // inputs.  Spectator handles come after all of the players.
+    if id == host {
+        for (i, spectator) in spectators.into_iter().enumerate() {
+            session_build = session_build
+                .add_player(PlayerType::Spectator(spectator), args.players + i)
+                .expect("Invalid spectator added.");
+        }
+    }
+
     // start the GGRS session
-    let channel = socket.take_channel(0).unwrap();
     let session = session_build
         .start_p2p_session(channel)
         .expect("Session could not be created.");
@@ -77,29 +263,98 @@ pub fn update_matchbox_socket(
     commands.insert_resource(Session::P2P(session));
 }
 
-pub fn handle_p2p_events(session: Option<ResMut<Session<ExampleGgrsConfig>>>) {
+pub fn handle_p2p_events(
+    mut commands: Commands,
+    mut desyncs: EventWriter<DesyncEvent>,
+    session: Option<ResMut<Session<ExampleGgrsConfig>>>,
+) {
     if let Some(mut session) = session {
-        if let Session::P2P(session) = session.as_mut() {
-            for event in session.events() {
-                info!("GGRS Event: {:?}", event);
-                match event {
-                    GgrsEvent::Disconnected { addr } => {
-                        panic!("Other player@{:?} disconnected", addr)
+        // Spectators get (mostly) the same events from their host
+        let events = match session.as_mut() {
+            Session::P2P(s) => s.events().collect::<Vec<_>>(),
+            Session::Spectator(s) => s.events().collect(),
+            Session::SyncTest(_) => Vec::new(),
+        };
+
+        for event in events {
+            info!("GGRS Event: {:?}", event);
+            match event {
+                GgrsEvent::Disconnected { addr } => {
+                    // Only a player leaving ends the match.  A spectator
+                    // leaving ours is no reason to stop playing, and when we
+                    // are the spectator, every disconnect is our host.
+                    let player_left = match session.as_ref() {
+                        Session::P2P(s) => s
+                            .handles_by_address(addr)
+                            .iter()
+                            .any(|h| s.remote_player_handles().contains(h)),
+                        _ => true,
+                    };
+                    if !player_left {
+                        info!("Spectator@{:?} stopped watching", addr);
+                        continue;
                     }
-                    GgrsEvent::DesyncDetected {
+
+                    warn!("Other player@{:?} disconnected", addr);
+
+                    // Tear down the match entirely.  Removing the session
+                    // stops bevy_ggrs from running our schedule, and
+                    // dropping the socket closes our connection to the
+                    // matchbox.  The world itself is reset and we
+                    // reconnect once `PeerLeft` shows up.
+                    commands.remove_resource::<Session<ExampleGgrsConfig>>();
+                    commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+                    commands.insert_resource(PeerLeft { peer: addr });
+                }
+                GgrsEvent::Synchronized { .. } => {
+                    // We have a new opponent, no need to keep telling
+                    // everyone about the old one.  Removing it also means
+                    // the next disconnect inserts it again, which is what
+                    // starts the next reset.
+                    commands.remove_resource::<PeerLeft>();
+                }
+                GgrsEvent::DesyncDetected {
+                    frame,
+                    local_checksum,
+                    remote_checksum,
+                    addr,
+                } => {
+                    // Keep going, but dump everything we know so we can
+                    // compare with the other peer's report afterwards
+                    desyncs.send(DesyncEvent {
                         frame,
                         local_checksum,
                         remote_checksum,
                         addr,
-                    } => {
-                        panic!(
-                            "Desync detected on frame {} local {} remote {}@{:?}",
-                            frame, local_checksum, remote_checksum, addr
-                        );
-                    }
-                    _ => (),
+                    });
                 }
+                _ => (),
             }
         }
     }
 }
+
+pub fn show_peer_left(mut commands: Commands, peer_left: Res<PeerLeft>) {
+    commands.spawn((
+        PeerLeftText,
+        TextBundle::from_section(
+            format!(
+                "Peer {:?} left the match.\nWaiting for a new player...",
+                peer_left.peer
+            ),
+            TextStyle::default(),
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.),
+            left: Val::Px(10.),
+            ..default()
+        }),
+    ));
+}
+
+pub fn hide_peer_left(mut commands: Commands, text: Query<Entity, With<PeerLeftText>>) {
+    for e in text.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+}
@@ -0,0 +1,95 @@
+use bevy::utils::Duration;
+
+use crate::prelude::*;
+
+/// The network stats overlay, toggled with `n`.  Stats are polled once a
+/// second, along with the rollback rates from [`RollbackMetrics`].
+#[derive(Resource)]
+pub struct NetworkStatsOverlay {
+    pub visible: bool,
+    pub timer: Timer,
+}
+
+impl Default for NetworkStatsOverlay {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            timer: Timer::new(Duration::from_secs(1), TimerMode::Repeating),
+        }
+    }
+}
+
+/// Marker for the overlay text
+#[derive(Component)]
+pub struct NetworkStatsText;
+
+pub fn spawn_network_stats(mut commands: Commands) {
+    commands.spawn((
+        NetworkStatsText,
+        TextBundle::from_section("", TextStyle::default()).with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.),
+            right: Val::Px(10.),
+            ..default()
+        }),
+    ));
+}
+
+/// Non-game input, like [`toggle_random_input`]
+pub fn toggle_network_stats(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut overlay: ResMut<NetworkStatsOverlay>,
+    mut text: Query<&mut Visibility, With<NetworkStatsText>>,
+) {
+    if keys.just_pressed(KeyCode::KeyN) {
+        overlay.visible = !overlay.visible;
+        for mut visibility in text.iter_mut() {
+            *visibility = if overlay.visible {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+    }
+}
+
+pub fn update_network_stats(
+    time: Res<Time>,
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    metrics: Res<RollbackMetrics>,
+    mut overlay: ResMut<NetworkStatsOverlay>,
+    mut text: Query<&mut Text, With<NetworkStatsText>>,
+) {
+    if !overlay.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let mut stats = format!(
+        "rollbacks/s: {}\nresimulated frames/s: {}\n",
+        metrics.rollbacks_per_second, metrics.resimulated_frames_per_second
+    );
+
+    if let Some(Session::P2P(session)) = session.as_deref() {
+        for handle in session.remote_player_handles() {
+            match session.network_stats(handle) {
+                Ok(s) => {
+                    stats += &format!(
+                        "\nplayer {}\n  ping: {} ms\n  frames behind (local/remote): {}/{}\n  send queue: {}\n  kbps sent: {}\n",
+                        handle,
+                        s.ping,
+                        s.local_frames_behind,
+                        s.remote_frames_behind,
+                        s.send_queue_len,
+                        s.kbps_sent
+                    )
+                }
+                // Stats are not available until we have been talking for a bit
+                Err(e) => stats += &format!("\nplayer {}\n  {}\n", handle, e),
+            }
+        }
+    }
+
+    for mut text in text.iter_mut() {
+        text.sections[0].value.clone_from(&stats);
+    }
+}
@@ -1,13 +1,27 @@
+use bevy::utils::Instant;
 use bevy_ggrs::RollbackFrameCount;
 
 use crate::prelude::*;
 
 /// Our physics rollback state container, which will be rolled back and we will
-/// use to restore our physics state.
-#[derive(Default, Reflect, Clone, Hash, Resource, PartialEq, Eq)]
+/// use to restore our physics state.  The snapshot itself lives in
+/// [`PhysicsSnapshots`], so this stays small enough to copy every frame.
+#[derive(Copy, Clone, Debug, Reflect, Hash, Resource, PartialEq, Eq)]
 #[reflect(Hash, Resource, PartialEq)]
 pub struct PhysicsRollbackState {
-    pub rapier_state: Vec<u8>,
+    /// The frame of the snapshot to restore when rolling back to this state
+    pub frame: Frame,
+    /// Checksum of that snapshot, which is what GGRS compares between peers
+    pub checksum: u64,
+}
+
+impl Default for PhysicsRollbackState {
+    fn default() -> Self {
+        Self {
+            frame: INITIAL_SNAPSHOT_FRAME,
+            checksum: 0,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Resource, Hash, Reflect)]
@@ -108,7 +122,10 @@ pub fn toggle_physics(
 pub fn rollback_rapier_context(
     rollback_status: Res<RollbackStatus>,
     game_state: Res<PhysicsRollbackState>,
+    mut snapshots: ResMut<PhysicsSnapshots>,
     mut rapier: ResMut<RapierContext>,
+    mut metrics: ResMut<RollbackMetrics>,
+    args: Res<Args>,
 ) {
     // Only restore our state if we are in a rollback.  This step is *critical*.
     // Only doing this during rollbacks saves us a step every frame.  Here, we
@@ -121,77 +138,86 @@ pub fn rollback_rapier_context(
     // return;
 
     if rollback_status.is_rollback && rollback_status.rollback_frame > 1 {
-        // Serialize our physics state for hashing, to display the state in-flight.
-        // This should not be necessary for this demo to work, as we will do the
-        // real checksum during `save_game_state` at the end of the pipeline.
-        // TODO:  Remove this for your real game.  It is unnecessary work!
-        log::info!(
-            "Context expected hash before rollback: {:?}",
-            game_state.rapier_state.reflect_hash()
-        );
-
-        if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
+        let diagnostics = args.desync_diagnostics;
+        if diagnostics >= DesyncDiagnostics::Checksums {
             log::info!(
-                "Context hash before rollback: {:?}",
-                context_bytes.reflect_hash()
+                "Context expected hash before rollback: {:?}",
+                game_state.checksum
             );
         }
 
-        if let Ok(context) = bincode::deserialize::<RapierContext>(game_state.rapier_state.as_ref())
-        {
-            // commands.insert_resource(context);
-            // *rapier = context;
-
-            // Inserting or replacing directly seems to screw up some of the
-            // crate-only properties.  So, we'll copy over each public
-            // property instead.
-            rapier.bodies = context.bodies;
-            rapier.broad_phase = context.broad_phase;
-            rapier.ccd_solver = context.ccd_solver;
-            rapier.colliders = context.colliders;
-            rapier.impulse_joints = context.impulse_joints;
-            rapier.integration_parameters = context.integration_parameters;
-            rapier.islands = context.islands;
-            rapier.multibody_joints = context.multibody_joints;
-            rapier.narrow_phase = context.narrow_phase;
-            rapier.query_pipeline = context.query_pipeline;
-
-            // pipeline is not serialized
-            // rapier.pipeline = context.pipeline;
+        // Serialize our physics state for hashing, to display the state
+        // in-flight.  This is not necessary for the rollback itself, as we do
+        // the real checksum during `save_rapier_context` at the end of the
+        // pipeline, so it is only done when asked for.
+        if diagnostics >= DesyncDiagnostics::Rehash {
+            if let Ok(checksum) = context_checksum(rapier.as_ref()) {
+                log::info!("Context hash before rollback: {:?}", checksum);
+            }
         }
 
-        // Again, not necessary for the demo, just to show the rollback changes
-        // as they occur.
-        // TODO:  Remove this for your real game.  It is unnecessary work!
-        if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
-            log::info!(
-                "Context hash after rollback: {:?}",
-                context_bytes.reflect_hash()
+        if !snapshots.contains(game_state.frame) {
+            // This would be a bug; GGRS should never take us back further
+            // than our ring buffer goes
+            log::error!(
+                "No physics snapshot for frame {} to roll back to",
+                game_state.frame
             );
+            return;
+        }
+
+        // commands.insert_resource(context);
+        // *rapier = context;
+        // Neither of those work, see `PhysicsSnapshots::restore`.
+        let started = Instant::now();
+        if let Err(e) = snapshots.restore(game_state.frame, &mut rapier) {
+            log::error!(
+                "Could not restore the physics snapshot for frame {}: {}",
+                game_state.frame,
+                e
+            );
+        }
+        metrics.record_restore(started.elapsed());
+
+        // Again, not necessary, just to show the rollback changes as they
+        // occur.  Should match the expected hash from above.
+        if diagnostics >= DesyncDiagnostics::Rehash {
+            if let Ok(checksum) = context_checksum(rapier.as_ref()) {
+                log::info!("Context hash after rollback: {:?}", checksum);
+            }
         }
     }
 }
 
 pub fn save_rapier_context(
     mut game_state: ResMut<PhysicsRollbackState>,
+    mut snapshots: ResMut<PhysicsSnapshots>,
+    current_frame: Res<RollbackFrameCount>,
     rapier: Res<RapierContext>,
+    mut metrics: ResMut<RollbackMetrics>,
+    args: Res<Args>,
 ) {
     // This serializes our context every frame.  It's not great, but works to
     // integrate the two plugins.  To do less of it, we would need to change
     // bevy_ggrs to serialize arbitrary structs like this one in addition to
     // component tracking.  If you need this to happen less, I'd recommend not
-    // using the plugin and implementing GGRS yourself.
-    if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
-        log::info!(
-            "Context hash before save: {:?}",
-            game_state.rapier_state.reflect_hash()
-        );
+    // using the plugin and implementing GGRS yourself.  At least the snapshot
+    // buffers get reused, so this does not allocate every frame, and with
+    // `--snapshot-format delta` only the parts that changed are kept.
+    let current_frame: i32 = (*current_frame).into();
+    let started = Instant::now();
+    let log_checksums = args.desync_diagnostics >= DesyncDiagnostics::Checksums;
+    if let Ok(checksum) = snapshots.save(current_frame, rapier.as_ref()) {
+        if log_checksums {
+            log::info!("Context hash before save: {:?}", game_state.checksum);
+        }
 
-        game_state.rapier_state = context_bytes;
+        game_state.frame = current_frame;
+        game_state.checksum = checksum;
 
-        log::info!(
-            "Context hash after save: {:?}",
-            game_state.rapier_state.reflect_hash()
-        );
+        if log_checksums {
+            log::info!("Context hash after save: {:?}", game_state.checksum);
+        }
     }
+    metrics.record_save(started.elapsed());
 }
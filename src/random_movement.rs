@@ -1,8 +1,10 @@
+use rand::RngCore;
+
 use crate::prelude::*;
 
 /// Controls whether our opponent will inject random inputs while inactive.
 /// This is useful for testing rollbacks locally and can be toggled off with `r`
-/// and `t`.
+/// and `t` (or whatever the [`InputMap`] says).
 #[derive(Default, Reflect, Hash, Resource, PartialEq, Eq)]
 #[reflect(Hash, Resource, PartialEq)]
 pub struct RandomInput {
@@ -10,11 +12,76 @@ pub struct RandomInput {
 }
 
 /// Non-game input.  Just chucking this into the stack carelessly.
-pub fn toggle_random_input(mut commands: Commands, keys: Res<ButtonInput<KeyCode>>) {
-    if keys.just_pressed(KeyCode::KeyR) {
+pub fn toggle_random_input(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    buttons: Res<ButtonInput<GamepadButton>>,
+    input_map: Res<InputMap>,
+    rebind_screen: Option<Res<RebindScreen>>,
+) {
+    // The keys might be getting rebound
+    if rebind_screen.is_some_and(|s| s.visible) {
+        return;
+    }
+
+    if input_map.random_on.just_pressed(&keys, &buttons) {
         commands.insert_resource(RandomInput { on: true });
     }
-    if keys.just_pressed(KeyCode::KeyT) {
+    if input_map.random_off.just_pressed(&keys, &buttons) {
         commands.insert_resource(RandomInput { on: false });
     }
 }
+
+/// Deterministic random numbers for anything in the simulation.  This is
+/// SplitMix64: tiny, `Copy`, and the same on every platform and version, so it
+/// can be rolled back and checksummed like any other resource.  Every peer must
+/// use the same `--seed`; if they do not, it shows up as a desync right away.
+#[derive(Copy, Clone, Debug, Default, Hash, Reflect, Resource, PartialEq, Eq)]
+#[reflect(Hash, Resource, PartialEq)]
+pub struct RollbackRng {
+    seed: u64,
+    state: u64,
+}
+
+impl RollbackRng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, state: seed }
+    }
+
+    /// A stream of its own for `handle` on `frame`, which only depends on the
+    /// seed.  Inputs are read outside of the rollback schedule, so this is what
+    /// they use: however many rollbacks happen, or however many numbers the
+    /// simulation takes, the same frame always gets the same numbers.
+    pub fn stream(&self, frame: Frame, handle: usize) -> Self {
+        let mut rng = Self::new(self.seed ^ (((frame as u32 as u64) << 32) | handle as u64));
+        // Mix once, so neighbouring frames do not start out looking alike
+        rng.state = rng.next_u64();
+        rng
+    }
+}
+
+impl RngCore for RollbackRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
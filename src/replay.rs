@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use bevy::app::AppExit;
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::Duration;
+use bevy_ggrs::{ConfirmedFrameCount, LocalInputs, LocalPlayers, RollbackFrameCount};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// The first bytes of every replay file
+pub const REPLAY_MAGIC: [u8; 4] = *b"BGRR";
+
+/// Bump this whenever the replay format, or anything that changes how a replay
+/// plays back (e.g., [`GGRSInput`]), changes
+pub const REPLAY_VERSION: u16 = 3;
+
+/// The settings a match was played with.  A replay can only be played back
+/// with the same ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplayHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub fps: u32,
+    pub players: u32,
+    pub seed: u64,
+    /// Whether `--extra-balls` was on, as it changes what happens every frame
+    pub extra_balls: bool,
+    /// The [`RollbackFrameCount`] of the first frame
+    pub first_frame: Frame,
+}
+
+impl ReplayHeader {
+    /// Takes the settings this was recorded with over the ones in `args`
+    pub fn apply(&self, args: &mut Args) {
+        args.players = self.players as usize;
+        args.seed = self.seed;
+        args.extra_balls = self.extra_balls;
+    }
+}
+
+/// One confirmed frame of a match
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplayFrame {
+    /// The [`GGRSInput`] of every handle, with disconnected players idle
+    pub inputs: Vec<GGRSInput>,
+    /// The [`PhysicsRollbackState`] checksum we ended the frame with, so a
+    /// replay can check it plays out the same way
+    pub checksum: u64,
+}
+
+/// Every frame we simulated this match, by frame.  Resimulated frames replace
+/// their predicted entries, so everything up to the confirmed frame is final.
+/// Like [`RollbackHistory`], this is left outside of the rollback system.
+#[derive(Default, Debug, Resource)]
+pub struct ReplayRecording(pub BTreeMap<Frame, ReplayFrame>);
+
+pub fn recording_replays(args: Res<Args>) -> bool {
+    args.replay_dir.is_some()
+}
+
+/// Runs after the context is saved, so the checksum is the one GGRS sees
+pub fn record_replay_frame(
+    current_frame: Res<RollbackFrameCount>,
+    inputs: Res<PlayerInputs<ExampleGgrsConfig>>,
+    game_state: Res<PhysicsRollbackState>,
+    mut recording: ResMut<ReplayRecording>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    let inputs = inputs
+        .iter()
+        .map(|(input, status)| match status {
+            // Same as `apply_inputs`
+            InputStatus::Disconnected => GGRSInput::default(),
+            _ => *input,
+        })
+        .collect();
+
+    recording.0.insert(
+        current_frame,
+        ReplayFrame {
+            inputs,
+            checksum: game_state.checksum,
+        },
+    );
+}
+
+impl ReplayRecording {
+    /// Every confirmed frame from the start of the match, if there are any
+    pub fn to_replay(&self, args: &Args, confirmed_frame: Frame) -> Option<Replay> {
+        // Only frames everyone agrees on, and only as long as there are no gaps
+        let first_frame = *self.0.keys().next()?;
+        let frames = (first_frame..=confirmed_frame)
+            .map_while(|frame| self.0.get(&frame).cloned())
+            .collect::<Vec<_>>();
+        if frames.is_empty() {
+            return None;
+        }
+
+        Some(Replay {
+            header: ReplayHeader {
+                magic: REPLAY_MAGIC,
+                version: REPLAY_VERSION,
+                fps: FPS as u32,
+                players: args.players as u32,
+                seed: args.seed,
+                extra_balls: args.extra_balls,
+                first_frame,
+            },
+            frames,
+        })
+    }
+}
+
+/// Writes the match so far as `<replay dir>/<timestamp>.replay`
+fn write_replay(args: &Args, recording: &ReplayRecording, confirmed_frame: Frame) {
+    let Some(dir) = &args.replay_dir else {
+        return;
+    };
+    let Some(replay) = recording.to_replay(args, confirmed_frame) else {
+        return;
+    };
+
+    let path = dir.join(format!("{}.replay", timestamp()));
+    let result = std::fs::create_dir_all(dir)
+        .map_err(|e| e.to_string())
+        .and_then(|_| replay.write(&path));
+
+    match result {
+        Ok(_) => info!(
+            "Replay of {} frames written to {:?}",
+            replay.frames.len(),
+            path
+        ),
+        Err(e) => error!("Could not write replay to {:?}: {}", path, e),
+    }
+}
+
+/// The match is over when our peer leaves.  Runs before we reset everything
+/// for the next one.
+pub fn write_replay_on_peer_left(
+    args: Res<Args>,
+    recording: Res<ReplayRecording>,
+    confirmed_frame: Res<ConfirmedFrameCount>,
+) {
+    write_replay(&args, &recording, (*confirmed_frame).into());
+}
+
+pub fn write_replay_on_exit(
+    mut exits: EventReader<AppExit>,
+    args: Res<Args>,
+    recording: Option<Res<ReplayRecording>>,
+    confirmed_frame: Option<Res<ConfirmedFrameCount>>,
+) {
+    if exits.read().next().is_none() {
+        return;
+    }
+
+    if let (Some(recording), Some(confirmed_frame)) = (recording, confirmed_frame) {
+        write_replay(&args, &recording, (*confirmed_frame).into());
+    }
+}
+
+/// How fast a replay plays back
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum ReplaySpeed {
+    /// As it was played
+    #[default]
+    Normal,
+    /// [`FAST_FORWARD_SPEED`] times as fast
+    FastForward,
+    /// As fast as we can simulate, one frame per update.  Best with
+    /// `--headless`, as a window waits for the screen.
+    Max,
+}
+
+/// How much faster than normal fast forward is
+pub const FAST_FORWARD_SPEED: f32 = 4.;
+
+/// A recorded match, as read back from a replay file
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub frames: Vec<ReplayFrame>,
+}
+
+impl Replay {
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let mut file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+
+        // The header on its own first, so a reader can check the version
+        // before trying to make sense of the rest
+        bincode::serialize_into(&mut file, &self.header)
+            .and_then(|_| bincode::serialize_into(&mut file, &self.frames))
+            .map_err(|e| e.to_string())?;
+        file.flush().map_err(|e| e.to_string())
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let mut file = std::io::BufReader::new(
+            std::fs::File::open(path).map_err(|e| format!("Could not open {:?}: {}", path, e))?,
+        );
+
+        let header: ReplayHeader =
+            bincode::deserialize_from(&mut file).map_err(|e| format!("Bad header: {}", e))?;
+        if header.magic != REPLAY_MAGIC {
+            return Err(format!("{:?} is not a replay", path));
+        }
+        if header.version != REPLAY_VERSION {
+            return Err(format!(
+                "Replay version {} can not be played back by version {}",
+                header.version, REPLAY_VERSION
+            ));
+        }
+        if header.fps != FPS as u32 {
+            return Err(format!(
+                "Replay was recorded at {} FPS, we run at {}",
+                header.fps, FPS
+            ));
+        }
+
+        let frames =
+            bincode::deserialize_from(&mut file).map_err(|e| format!("Bad frames: {}", e))?;
+        Ok(Self { header, frames })
+    }
+}
+
+/// The replay we are playing back, and how it is going so far
+#[derive(Resource, Debug)]
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    /// The newest frame we have checked against the replay
+    pub verified_frame: Option<Frame>,
+    /// Frames whose checksum was not the recorded one
+    pub mismatches: usize,
+    pub finished: bool,
+    /// The recorded frame whose inputs [`replay_input`] hands GGRS next
+    pub next_input_frame: Frame,
+}
+
+impl ReplayPlayback {
+    /// Reads `--replay`, and takes the settings it was recorded with over the
+    /// ones we were given, as it can only be played back with those
+    pub fn load(args: &mut Args) -> Self {
+        let path = args
+            .replay
+            .clone()
+            .expect("--replay is required to play back a replay");
+        let replay = Replay::read(&path).unwrap_or_else(|e| panic!("{}", e));
+
+        replay.header.apply(args);
+
+        Self::new(replay)
+    }
+
+    pub fn new(replay: Replay) -> Self {
+        // GGRS holds local inputs back by the session's input delay, so the
+        // inputs we give it are always for a frame that far ahead.  The frames
+        // before that get no input at all, just like when they were recorded.
+        let next_input_frame = replay.header.first_frame + INPUT_DELAY as Frame;
+        Self {
+            replay,
+            verified_frame: None,
+            mismatches: 0,
+            finished: false,
+            next_input_frame,
+        }
+    }
+
+    /// The recorded frame for `frame`, if the replay has it
+    pub fn frame(&self, frame: Frame) -> Option<&ReplayFrame> {
+        usize::try_from(frame - self.replay.header.first_frame)
+            .ok()
+            .and_then(|i| self.replay.frames.get(i))
+    }
+
+    /// The last frame of the replay
+    pub fn last_frame(&self) -> Frame {
+        self.replay.header.first_frame + self.replay.frames.len() as Frame - 1
+    }
+}
+
+/// Everything a replay run needs on top of the usual app.  The session itself
+/// is started in `connect`, like any other.
+pub fn add_replay_playback(app: &mut App, playback: ReplayPlayback, speed: ReplaySpeed) {
+    app.insert_resource(playback)
+        .add_systems(Update, finish_replay);
+
+    match speed {
+        ReplaySpeed::Normal => {}
+        // GGRS runs as many frames as the time that passed calls for
+        ReplaySpeed::FastForward => app
+            .world_mut()
+            .resource_mut::<Time<Virtual>>()
+            .set_relative_speed(FAST_FORWARD_SPEED),
+        // Pretend exactly one frame of time passes every update, however
+        // long it actually took
+        ReplaySpeed::Max => {
+            app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+                1. / FPS as f64,
+            )));
+        }
+    }
+}
+
+/// Takes the place of [`input`].  Every player is local to a replay's session,
+/// and gets the input they had on the recorded frame GGRS will apply this to,
+/// so the recorded inputs go through GGRS like any others.  Past the end of the
+/// replay, everyone stands still.
+pub fn replay_input(
+    mut commands: Commands,
+    local_players: Res<LocalPlayers>,
+    mut playback: ResMut<ReplayPlayback>,
+) {
+    let recorded = playback.frame(playback.next_input_frame);
+    let local_inputs = local_players
+        .0
+        .iter()
+        .map(|handle| {
+            let input = recorded
+                .and_then(|f| f.inputs.get(*handle).copied())
+                .unwrap_or_default();
+            (*handle, input)
+        })
+        .collect();
+    commands.insert_resource(LocalInputs::<ExampleGgrsConfig>(local_inputs));
+
+    // Once per frame GGRS advances, as that is when it asks for our inputs
+    playback.next_input_frame += 1;
+}
+
+/// Checks each frame ends with the checksum it was recorded with
+pub fn verify_replay_frame(
+    current_frame: Res<RollbackFrameCount>,
+    game_state: Res<PhysicsRollbackState>,
+    mut playback: ResMut<ReplayPlayback>,
+) {
+    let current_frame: i32 = (*current_frame).into();
+    let Some(expected) = playback.frame(current_frame).map(|f| f.checksum) else {
+        return;
+    };
+
+    if game_state.checksum != expected {
+        if playback.mismatches == 0 {
+            error!(
+                "Replay diverged on frame {}: expected checksum {} but got {}",
+                current_frame, expected, game_state.checksum
+            );
+        }
+        playback.mismatches += 1;
+    }
+    playback.verified_frame = Some(current_frame);
+}
+
+/// Stops the session once every recorded frame has been played and checked.
+/// Headless, we exit too, with an error if the replay did not play out the
+/// way it was recorded.
+pub fn finish_replay(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    args: Res<Args>,
+    mut exits: EventWriter<AppExit>,
+) {
+    if playback.finished || playback.verified_frame < Some(playback.last_frame()) {
+        return;
+    }
+    playback.finished = true;
+    commands.remove_resource::<Session<ExampleGgrsConfig>>();
+
+    let frames = playback.replay.frames.len();
+    if playback.mismatches == 0 {
+        info!("Replay finished: all {} frames matched", frames);
+    } else {
+        error!(
+            "Replay finished: {} of {} frames did not match",
+            playback.mismatches, frames
+        );
+    }
+
+    if args.headless {
+        exits.send(if playback.mismatches == 0 {
+            AppExit::Success
+        } else {
+            AppExit::error()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay() -> Replay {
+        Replay {
+            header: ReplayHeader {
+                magic: REPLAY_MAGIC,
+                version: REPLAY_VERSION,
+                fps: FPS as u32,
+                players: 2,
+                seed: 42,
+                extra_balls: true,
+                first_frame: 0,
+            },
+            frames: (0..3)
+                .map(|i| ReplayFrame {
+                    inputs: vec![
+                        GGRSInput {
+                            input: INPUT_JUMP,
+                            x: i,
+                            y: -i,
+                        },
+                        GGRSInput::default(),
+                    ],
+                    checksum: i as u64,
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes `replay` somewhere of its own, and reads it straight back
+    fn round_trip(name: &str, replay: &Replay) -> Result<Replay, String> {
+        let path = std::env::temp_dir().join(format!(
+            "bevy-ggrs-rapier-example-{}-{}.replay",
+            name,
+            std::process::id()
+        ));
+        replay.write(&path).unwrap();
+        let read = Replay::read(&path);
+        let _ = std::fs::remove_file(&path);
+        read
+    }
+
+    #[test]
+    fn replays_read_back_what_was_written() {
+        let written = replay();
+        let read = round_trip("round-trip", &written).unwrap();
+        assert_eq!(read.header, written.header);
+        assert_eq!(read.frames, written.frames);
+    }
+
+    #[test]
+    fn replays_with_the_wrong_magic_are_rejected() {
+        let mut replay = replay();
+        replay.header.magic = *b"NOPE";
+        assert!(round_trip("magic", &replay).is_err());
+    }
+
+    #[test]
+    fn replays_from_another_version_are_rejected() {
+        let mut replay = replay();
+        replay.header.version = REPLAY_VERSION + 1;
+        assert!(round_trip("version", &replay).is_err());
+    }
+
+    #[test]
+    fn replays_at_another_fps_are_rejected() {
+        let mut replay = replay();
+        replay.header.fps = FPS as u32 * 2;
+        assert!(round_trip("fps", &replay).is_err());
+    }
+}
@@ -1,14 +1,33 @@
 use bevy::utils::HashMap;
-use bevy_ggrs::{LocalInputs, LocalPlayers};
+use bevy_ggrs::{LocalInputs, LocalPlayers, RollbackFrameCount};
 use bevy_matchbox::prelude::PeerId;
+use serde::{Deserialize, Serialize};
 
 use crate::prelude::*;
 
 // These are just 16 bit for bit-packing alignment in the input struct
-const INPUT_UP: u16 = 0b00001;
-const INPUT_DOWN: u16 = 0b00010;
-const INPUT_LEFT: u16 = 0b00100;
-const INPUT_RIGHT: u16 = 0b01000;
+pub const INPUT_UP: u16 = 0b0000001;
+pub const INPUT_DOWN: u16 = 0b0000010;
+pub const INPUT_LEFT: u16 = 0b0000100;
+pub const INPUT_RIGHT: u16 = 0b0001000;
+// Abilities, see `apply_abilities`
+pub const INPUT_JUMP: u16 = 0b0010000;
+pub const INPUT_DASH: u16 = 0b0100000;
+pub const INPUT_KICK: u16 = 0b1000000;
+
+/// The bit each [`Action`] we send sets, whatever it is bound to
+const INPUT_ACTIONS: [(Action, u16); 7] = [
+    (Action::Up, INPUT_UP),
+    (Action::Down, INPUT_DOWN),
+    (Action::Left, INPUT_LEFT),
+    (Action::Right, INPUT_RIGHT),
+    (Action::Jump, INPUT_JUMP),
+    (Action::Dash, INPUT_DASH),
+    (Action::Kick, INPUT_KICK),
+];
+
+/// Stick axes are sent as `i8`s, so full tilt is this
+const AXIS_MAX: f32 = i8::MAX as f32;
 
 /// GGRS player handle, we use this to associate GGRS handles back to our [`Entity`]
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Component)]
@@ -20,46 +39,129 @@ pub struct Player {
 pub type ExampleGgrsConfig = bevy_ggrs::GgrsConfig<GGRSInput, PeerId>;
 
 /// Our primary data struct; what players send to one another
+// Keep the fields ordered so there is no padding, or it will not be `Pod`
 #[repr(C)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Pod, Zeroable)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Pod, Zeroable, Serialize, Deserialize)]
 pub struct GGRSInput {
     // The input from our player
     pub input: u16,
+    // The left stick of their gamepad, quantized from -1.0..=1.0 so it is the
+    // same on every peer and only a byte each on the wire
+    pub x: i8,
+    pub y: i8,
+}
+
+impl GGRSInput {
+    pub fn is_idle(&self) -> bool {
+        self.input == 0 && self.x == 0 && self.y == 0
+    }
+
+    /// Which way the player is pushing, from -1.0 to 1.0 on each axis
+    pub fn direction(&self) -> Vec2 {
+        let right = self.input & INPUT_RIGHT != 0;
+        let left = self.input & INPUT_LEFT != 0;
+        let up = self.input & INPUT_UP != 0;
+        let down = self.input & INPUT_DOWN != 0;
+
+        let direction_right = right && !left;
+        let direction_left = left && !right;
+        let direction_up = up && !down;
+        let direction_down = down && !up;
+
+        // The d-pad and keys push at full speed, the stick in proportion to how
+        // far it is tilted.  Dividing the quantized value is the same on
+        // every peer, unlike the raw stick value.
+        let horizontal = if direction_left {
+            -1.
+        } else if direction_right {
+            1.
+        } else {
+            self.x as f32 / AXIS_MAX
+        };
+
+        let vertical = if direction_down {
+            -1.
+        } else if direction_up {
+            1.
+        } else {
+            self.y as f32 / AXIS_MAX
+        };
+
+        Vec2::new(horizontal, vertical)
+    }
+}
+
+/// Quantizes a stick axis for [`GGRSInput`]
+fn quantize_axis(value: f32) -> i8 {
+    (value.clamp(-1., 1.) * AXIS_MAX).round() as i8
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn input(
     mut commands: Commands,
     local_players: Res<LocalPlayers>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
+    input_map: Res<InputMap>,
+    rebind_screen: Option<Res<RebindScreen>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<ButtonInput<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
     mut random: ResMut<RandomInput>,
+    rollback_rng: Res<RollbackRng>,
+    current_frame: Res<RollbackFrameCount>,
     physics_enabled: Res<PhysicsEnabled>,
 ) {
+    let current_frame: i32 = (*current_frame).into();
+
     let mut local_inputs = HashMap::new();
+    let rebinding = rebind_screen.is_some_and(|s| s.visible);
+
+    let mut gamepads = gamepads.iter().collect::<Vec<_>>();
+    gamepads.sort_by_key(|g| g.id);
 
-    for handle in &local_players.0 {
+    for (i, handle) in local_players.0.iter().enumerate() {
         let mut input: u16 = 0;
+        let mut x: i8 = 0;
+        let mut y: i8 = 0;
 
         // Do not do anything until physics are live
         if physics_enabled.0 {
-            // Build the input
-            if keyboard_input.pressed(KeyCode::KeyW) {
-                input |= INPUT_UP;
-            }
-            if keyboard_input.pressed(KeyCode::KeyA) {
-                input |= INPUT_LEFT;
-            }
-            if keyboard_input.pressed(KeyCode::KeyS) {
-                input |= INPUT_DOWN;
+            // Each local player gets a gamepad of their own, in the order they
+            // were connected.  The keyboard still drives all of them.
+            let gamepad = gamepads.get(i).copied();
+
+            // Build the input, unless the keys are being rebound
+            if !rebinding {
+                for (action, bit) in INPUT_ACTIONS {
+                    if input_map
+                        .binding(action)
+                        .pressed(&keyboard_input, &gamepad_buttons, gamepad)
+                    {
+                        input |= bit;
+                    }
+                }
             }
-            if keyboard_input.pressed(KeyCode::KeyD) {
-                input |= INPUT_RIGHT;
+
+            if let Some(gamepad) = gamepad.filter(|_| !rebinding) {
+                // Dead zones are already applied by Bevy's gamepad settings
+                let axis = |axis_type| {
+                    gamepad_axes
+                        .get(GamepadAxis::new(gamepad, axis_type))
+                        .map_or(0, quantize_axis)
+                };
+                x = axis(GamepadAxisType::LeftStickX);
+                y = axis(GamepadAxisType::LeftStickY);
             }
 
+            let moved = !GGRSInput { input, x, y }.is_idle();
+
             // toggle off random input if our local moves at all
-            if input != 0 && random.on {
+            if moved && random.on {
                 random.on = false;
-            } else if input == 0 && random.on {
-                let mut rng = thread_rng();
+            } else if !moved && random.on {
+                // Seeded, so a soak test with random input can be run again
+                // exactly
+                let mut rng = rollback_rng.stream(current_frame, *handle);
                 // Return a random input sometimes, or maybe nothing.
                 // Helps to trigger input-based rollbacks from the unplayed side
                 match rng.gen_range(0..10) {
@@ -72,28 +174,34 @@ pub fn input(
             }
         }
 
-        local_inputs.insert(*handle, GGRSInput { input });
+        local_inputs.insert(*handle, GGRSInput { input, x, y });
     }
 
     commands.insert_resource(LocalInputs::<ExampleGgrsConfig>(local_inputs));
 }
 
 pub fn apply_inputs(
-    mut query: Query<(&mut Velocity, &Player)>,
+    mut query: Query<(&mut Velocity, &Player, Option<&AbilityCooldowns>)>,
     inputs: Res<PlayerInputs<ExampleGgrsConfig>>,
     physics_enabled: Res<PhysicsEnabled>,
 ) {
-    for (mut v, p) in query.iter_mut() {
+    for (mut v, p, cooldowns) in query.iter_mut() {
         let (game_input, input_status) = inputs[p.handle];
-        let input = match input_status {
-            InputStatus::Confirmed => game_input.input,
-            InputStatus::Predicted => game_input.input,
-            InputStatus::Disconnected => 0, // disconnected players do nothing
+        let game_input = match input_status {
+            InputStatus::Confirmed => game_input,
+            InputStatus::Predicted => game_input,
+            InputStatus::Disconnected => GGRSInput::default(), // disconnected players do nothing
         };
-
-        if input > 0 {
+        if !game_input.is_idle() {
             // Useful for desync observing
-            log::info!("input {:?} from {}: {}", input_status, p.handle, input)
+            log::info!(
+                "input {:?} from {}: {} ({}, {})",
+                input_status,
+                p.handle,
+                game_input.input,
+                game_input.x,
+                game_input.y
+            )
         }
 
         // Do not do anything until physics are live
@@ -102,40 +210,27 @@ pub fn apply_inputs(
             continue;
         }
 
-        let right = input & INPUT_RIGHT != 0;
-        let left = input & INPUT_LEFT != 0;
-        let up = input & INPUT_UP != 0;
-        let down = input & INPUT_DOWN != 0;
+        let Vec2 {
+            x: horizontal,
+            y: vertical,
+        } = game_input.direction();
 
-        let direction_right = right && !left;
-        let direction_left = left && !right;
-        let direction_up = up && !down;
-        let direction_down = down && !up;
-
-        let horizontal = if direction_left {
-            -1.
-        } else if direction_right {
-            1.
-        } else {
-            0.
-        };
-
-        let vertical = if direction_down {
-            -1.
-        } else if direction_up {
-            1.
-        } else {
-            0.
-        };
+        // Right after a jump, dash or kick, let the impulse carry us instead
+        // of stopping dead
+        let momentum = cooldowns.is_some_and(|c| c.momentum > 0);
 
         let new_vel_x = if horizontal != 0. {
             v.linvel.x + horizontal * 10.0
+        } else if momentum {
+            v.linvel.x
         } else {
             0.
         };
 
         let new_vel_y = if vertical != 0. {
             v.linvel.y + vertical * 10.0
+        } else if momentum {
+            v.linvel.y
         } else {
             0.
         };
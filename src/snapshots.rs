@@ -0,0 +1,443 @@
+use std::hash::BuildHasher;
+
+use bevy::prelude::Resource;
+use bevy::utils::FixedState;
+use bevy_rapier2d::plugin::RapierContext;
+use clap::ValueEnum;
+use ggrs::Frame;
+
+use crate::MAX_PREDICTION;
+
+/// GGRS never rolls back further than the prediction window, so this is how
+/// many snapshots we need to keep.  Plus one for the frame being confirmed, and
+/// one more for the frame currently being simulated.
+pub const SNAPSHOT_SLOTS: usize = MAX_PREDICTION + 2;
+
+/// The frame the snapshot taken at startup is filed under.  It is older than
+/// any frame GGRS will ask us about.
+pub const INITIAL_SNAPSHOT_FRAME: Frame = -1;
+
+/// The parts of the [`RapierContext`] we snapshot, each serialized on its own.
+/// These are the same public properties we have always copied back over when
+/// rolling back.
+pub const SNAPSHOT_SETS: [&str; 10] = [
+    "bodies",
+    "colliders",
+    "broad_phase",
+    "narrow_phase",
+    "islands",
+    "ccd_solver",
+    "impulse_joints",
+    "multibody_joints",
+    "integration_parameters",
+    "query_pipeline",
+];
+
+const SET_COUNT: usize = SNAPSHOT_SETS.len();
+
+/// Delta snapshots compare each set with the frame before in chunks this many
+/// bytes long, and only keep the chunks that changed.  Small enough that a body
+/// sitting still, or a static collider, is left out on its own, even when
+/// something next to it in the same set moved.
+pub const SNAPSHOT_CHUNK_BYTES: usize = 64;
+
+/// How much of the context each snapshot stores
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum SnapshotFormat {
+    /// Every set, every frame
+    Full,
+    /// Only the chunks of each set that changed since the frame before.  The
+    /// oldest snapshot we keep is always complete, and serves as the base the
+    /// newer ones are deltas of.
+    #[default]
+    Delta,
+}
+
+#[derive(Default)]
+struct SetSnapshot {
+    /// When false, `bytes` only has the chunks listed in `chunks`, one after
+    /// another, and everything else is the same as the frame before
+    complete: bool,
+    bytes: Vec<u8>,
+    chunks: Vec<u32>,
+    /// How long the whole set is
+    len: usize,
+    checksum: u64,
+}
+
+impl SetSnapshot {
+    fn store_complete(&mut self, current: &[u8]) {
+        self.complete = true;
+        self.bytes.clear();
+        self.bytes.extend_from_slice(current);
+        self.chunks.clear();
+        self.len = current.len();
+    }
+
+    fn store_delta(&mut self, previous: &[u8], current: &[u8]) {
+        self.complete = false;
+        self.bytes.clear();
+        self.chunks.clear();
+        self.len = current.len();
+        for (i, chunk) in current.chunks(SNAPSHOT_CHUNK_BYTES).enumerate() {
+            let start = i * SNAPSHOT_CHUNK_BYTES;
+            if previous.get(start..start + chunk.len()) != Some(chunk) {
+                self.chunks.push(i as u32);
+                self.bytes.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    /// Turns the set as of the frame before into the set as of this one
+    fn apply(&self, set: &mut Vec<u8>) {
+        if self.complete {
+            set.clone_from(&self.bytes);
+            return;
+        }
+
+        set.resize(self.len, 0);
+        // Only the very last chunk of a set can be short, so the stored chunks
+        // line up with `chunks`
+        for (chunk, bytes) in self
+            .chunks
+            .iter()
+            .zip(self.bytes.chunks(SNAPSHOT_CHUNK_BYTES))
+        {
+            let start = *chunk as usize * SNAPSHOT_CHUNK_BYTES;
+            set[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    fn stored_bytes(&self) -> usize {
+        self.bytes.len() + self.chunks.len() * std::mem::size_of::<u32>()
+    }
+}
+
+#[derive(Default)]
+struct PhysicsSnapshot {
+    frame: Option<Frame>,
+    sets: [SetSnapshot; SET_COUNT],
+    checksum: u64,
+}
+
+/// Serialized [`RapierContext`]s, kept by frame in a ring buffer.
+///
+/// This is *not* rolled back.  Instead, the small [`PhysicsRollbackState`] is,
+/// and it tells us which frame's snapshot to restore.  Buffers are reused
+/// rather than dropped, so once they have grown to fit a context, saving a
+/// snapshot does not allocate at all.
+#[derive(Resource)]
+pub struct PhysicsSnapshots {
+    format: SnapshotFormat,
+    slots: Vec<PhysicsSnapshot>,
+    /// Every set as of the last frame we saved or restored, whole, for the
+    /// next delta to be taken against
+    latest: Option<Frame>,
+    latest_sets: [Vec<u8>; SET_COUNT],
+    scratch: Vec<u8>,
+}
+
+impl Default for PhysicsSnapshots {
+    fn default() -> Self {
+        Self::new(SnapshotFormat::default())
+    }
+}
+
+impl PhysicsSnapshots {
+    pub fn new(format: SnapshotFormat) -> Self {
+        Self {
+            format,
+            slots: (0..SNAPSHOT_SLOTS)
+                .map(|_| PhysicsSnapshot::default())
+                .collect(),
+            latest: None,
+            latest_sets: Default::default(),
+            scratch: Vec::new(),
+        }
+    }
+
+    fn slot(frame: Frame) -> usize {
+        frame.rem_euclid(SNAPSHOT_SLOTS as Frame) as usize
+    }
+
+    /// Whether we still have the snapshot for `frame`
+    pub fn contains(&self, frame: Frame) -> bool {
+        self.slots[Self::slot(frame)].frame == Some(frame)
+    }
+
+    /// Rebuilds `set` as of `frame` into `bytes`, starting from the newest
+    /// complete snapshot at or before it and applying every delta since
+    fn reconstruct(&self, frame: Frame, set: usize, bytes: &mut Vec<u8>) -> bool {
+        let Some(base) = (frame - SNAPSHOT_SLOTS as Frame + 1..=frame)
+            .rev()
+            .take_while(|f| self.contains(*f))
+            .find(|f| self.slots[Self::slot(*f)].sets[set].complete)
+        else {
+            return false;
+        };
+
+        for f in base..=frame {
+            self.slots[Self::slot(f)].sets[set].apply(bytes);
+        }
+        true
+    }
+
+    /// Makes `frame` the one `latest_sets` holds, if we have it
+    fn load_latest(&mut self, frame: Frame) -> bool {
+        if self.latest == Some(frame) {
+            return true;
+        }
+
+        self.latest = None;
+        for set in 0..SET_COUNT {
+            let mut bytes = std::mem::take(&mut self.latest_sets[set]);
+            let found = self.reconstruct(frame, set, &mut bytes);
+            self.latest_sets[set] = bytes;
+            if !found {
+                return false;
+            }
+        }
+        self.latest = Some(frame);
+        true
+    }
+
+    /// Serializes `context` as the snapshot for `frame`, replacing whatever
+    /// was in its slot, and returns the snapshot's checksum.  The checksum is
+    /// the same whichever format we use.
+    ///
+    /// Every set is serialized either way, as the checksum GGRS compares
+    /// covers all of it.  What the delta format saves is how much we keep.
+    pub fn save(&mut self, frame: Frame, context: &RapierContext) -> bincode::Result<u64> {
+        self.promote_base(frame);
+
+        // Anything newer was predicted from a past we are now rewriting, and
+        // is about to be saved again
+        for snapshot in self.slots.iter_mut() {
+            if snapshot.frame.is_some_and(|f| f >= frame) {
+                snapshot.frame = None;
+            }
+        }
+
+        let delta = self.format == SnapshotFormat::Delta && self.load_latest(frame - 1);
+        self.latest = None;
+
+        let slot = Self::slot(frame);
+        for set in 0..SET_COUNT {
+            self.scratch.clear();
+            serialize_set(set, context, &mut self.scratch)?;
+
+            let snapshot = &mut self.slots[slot].sets[set];
+            if delta {
+                snapshot.store_delta(&self.latest_sets[set], &self.scratch);
+            } else {
+                snapshot.store_complete(&self.scratch);
+            }
+            snapshot.checksum = checksum(&self.scratch);
+            std::mem::swap(&mut self.latest_sets[set], &mut self.scratch);
+        }
+
+        // Hash the sets' checksums, so this matches `context_checksum`
+        let set_checksums: [u64; SET_COUNT] =
+            std::array::from_fn(|set| self.slots[slot].sets[set].checksum);
+        let snapshot = &mut self.slots[slot];
+        snapshot.checksum = FixedState.hash_one(set_checksums);
+        snapshot.frame = Some(frame);
+        self.latest = Some(frame);
+
+        Ok(snapshot.checksum)
+    }
+
+    /// Saving `frame` evicts the oldest snapshot, our base.  Before it goes,
+    /// rebuild the next oldest snapshot in full, so that one becomes a
+    /// complete base in its place.
+    fn promote_base(&mut self, frame: Frame) {
+        let next = frame - SNAPSHOT_SLOTS as Frame + 1;
+        if !self.contains(next) {
+            return;
+        }
+
+        let slot = Self::slot(next);
+        for set in 0..SET_COUNT {
+            if self.slots[slot].sets[set].complete {
+                continue;
+            }
+
+            let mut bytes = std::mem::take(&mut self.scratch);
+            if self.reconstruct(next, set, &mut bytes) {
+                // The delta's buffer is spare now
+                let snapshot = &mut self.slots[slot].sets[set];
+                std::mem::swap(&mut snapshot.bytes, &mut bytes);
+                snapshot.complete = true;
+                snapshot.chunks.clear();
+            }
+            self.scratch = bytes;
+        }
+    }
+
+    /// Copies the snapshot for `frame` back over each set in `context`
+    pub fn restore(&mut self, frame: Frame, context: &mut RapierContext) -> bincode::Result<()> {
+        if !self.load_latest(frame) {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "No complete snapshot for frame {}",
+                frame
+            ))));
+        }
+
+        for (set, bytes) in self.latest_sets.iter().enumerate() {
+            deserialize_set(set, bytes, context)?;
+        }
+        Ok(())
+    }
+
+    /// Every set of the snapshot for `frame`, by name
+    pub fn sets(&self, frame: Frame) -> Vec<(&'static str, Vec<u8>)> {
+        (0..SET_COUNT)
+            .filter_map(|set| {
+                let mut bytes = Vec::new();
+                self.reconstruct(frame, set, &mut bytes)
+                    .then_some((SNAPSHOT_SETS[set], bytes))
+            })
+            .collect()
+    }
+
+    /// How many bytes the snapshot for `frame` takes up on its own, not
+    /// counting what it shares with earlier frames
+    pub fn stored_bytes(&self, frame: Frame) -> usize {
+        if !self.contains(frame) {
+            return 0;
+        }
+        self.slots[Self::slot(frame)]
+            .sets
+            .iter()
+            .map(SetSnapshot::stored_bytes)
+            .sum()
+    }
+}
+
+fn serialize_set(set: usize, context: &RapierContext, bytes: &mut Vec<u8>) -> bincode::Result<()> {
+    match set {
+        0 => bincode::serialize_into(bytes, &context.bodies),
+        1 => bincode::serialize_into(bytes, &context.colliders),
+        2 => bincode::serialize_into(bytes, &context.broad_phase),
+        3 => bincode::serialize_into(bytes, &context.narrow_phase),
+        4 => bincode::serialize_into(bytes, &context.islands),
+        5 => bincode::serialize_into(bytes, &context.ccd_solver),
+        6 => bincode::serialize_into(bytes, &context.impulse_joints),
+        7 => bincode::serialize_into(bytes, &context.multibody_joints),
+        8 => bincode::serialize_into(bytes, &context.integration_parameters),
+        9 => bincode::serialize_into(bytes, &context.query_pipeline),
+        _ => unreachable!("There are only {} snapshot sets", SET_COUNT),
+    }
+}
+
+fn deserialize_set(set: usize, bytes: &[u8], context: &mut RapierContext) -> bincode::Result<()> {
+    // Inserting or replacing the context directly seems to screw up some of
+    // the crate-only properties.  So, we'll copy over each public property
+    // instead.  The pipeline is not serialized.
+    match set {
+        0 => context.bodies = bincode::deserialize(bytes)?,
+        1 => context.colliders = bincode::deserialize(bytes)?,
+        2 => context.broad_phase = bincode::deserialize(bytes)?,
+        3 => context.narrow_phase = bincode::deserialize(bytes)?,
+        4 => context.islands = bincode::deserialize(bytes)?,
+        5 => context.ccd_solver = bincode::deserialize(bytes)?,
+        6 => context.impulse_joints = bincode::deserialize(bytes)?,
+        7 => context.multibody_joints = bincode::deserialize(bytes)?,
+        8 => context.integration_parameters = bincode::deserialize(bytes)?,
+        9 => context.query_pipeline = bincode::deserialize(bytes)?,
+        _ => unreachable!("There are only {} snapshot sets", SET_COUNT),
+    }
+    Ok(())
+}
+
+/// A checksum that comes out the same on every peer
+pub fn checksum(bytes: &[u8]) -> u64 {
+    FixedState.hash_one(bytes)
+}
+
+/// The checksum [`PhysicsSnapshots::save`] would give `context`, without
+/// keeping a snapshot of it
+pub fn context_checksum(context: &RapierContext) -> bincode::Result<u64> {
+    let mut bytes = Vec::new();
+    let mut set_checksums = [0; SET_COUNT];
+    for (set, set_checksum) in set_checksums.iter_mut().enumerate() {
+        bytes.clear();
+        serialize_set(set, context, &mut bytes)?;
+        *set_checksum = checksum(&bytes);
+    }
+    Ok(FixedState.hash_one(set_checksums))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_rapier2d::rapier::math::Vector;
+    use bevy_rapier2d::rapier::prelude::{ColliderBuilder, RigidBodyBuilder};
+
+    use super::*;
+
+    /// Drops a ball onto a floor, and hands the context to `f` after every
+    /// step.  The floor never changes and the ball always does, which is
+    /// what the delta format has to get right.
+    fn for_each_context(frames: usize, mut f: impl FnMut(Frame, &RapierContext)) {
+        let mut c = RapierContext::default();
+        let floor = c
+            .bodies
+            .insert(RigidBodyBuilder::fixed().translation(Vector::new(0., -100.)));
+        c.colliders
+            .insert_with_parent(ColliderBuilder::cuboid(100., 10.), floor, &mut c.bodies);
+        let ball = c
+            .bodies
+            .insert(RigidBodyBuilder::dynamic().translation(Vector::new(0., -80.)));
+        c.colliders.insert_with_parent(
+            ColliderBuilder::ball(4.).restitution(1.),
+            ball,
+            &mut c.bodies,
+        );
+
+        for frame in 0..frames as Frame {
+            c.pipeline.step(
+                &Vector::new(0., -98.1),
+                &c.integration_parameters,
+                &mut c.islands,
+                &mut c.broad_phase,
+                &mut c.narrow_phase,
+                &mut c.bodies,
+                &mut c.colliders,
+                &mut c.impulse_joints,
+                &mut c.multibody_joints,
+                &mut c.ccd_solver,
+                Some(&mut c.query_pipeline),
+                &(),
+                &(),
+            );
+            f(frame, &c);
+        }
+    }
+
+    #[test]
+    fn delta_snapshots_match_full_snapshots() {
+        let mut full = PhysicsSnapshots::new(SnapshotFormat::Full);
+        let mut delta = PhysicsSnapshots::new(SnapshotFormat::Delta);
+        let mut checksums = Vec::new();
+
+        // Go around the ring a few times, so bases get promoted
+        for_each_context(SNAPSHOT_SLOTS * 3, |frame, context| {
+            let checksum = full.save(frame, context).unwrap();
+            assert_eq!(checksum, delta.save(frame, context).unwrap());
+            checksums.push(checksum);
+        });
+
+        let newest = checksums.len() as Frame - 1;
+        for frame in newest - SNAPSHOT_SLOTS as Frame + 1..=newest {
+            assert_eq!(full.sets(frame), delta.sets(frame));
+
+            let mut restored = RapierContext::default();
+            delta.restore(frame, &mut restored).unwrap();
+            let resaved = PhysicsSnapshots::new(SnapshotFormat::Full)
+                .save(frame, &restored)
+                .unwrap();
+            assert_eq!(resaved, checksums[frame as usize]);
+        }
+        assert!(!delta.contains(newest - SNAPSHOT_SLOTS as Frame));
+    }
+}
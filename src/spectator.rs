@@ -0,0 +1,87 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::SocketAddr;
+
+#[cfg(not(target_arch = "wasm32"))]
+use bevy_ggrs::LocalPlayers;
+#[cfg(not(target_arch = "wasm32"))]
+use ggrs::UdpNonBlockingSocket;
+
+use crate::prelude::*;
+
+/// Marker for the text telling a spectator how far behind the host they are
+#[derive(Component)]
+pub struct SpectatorHud;
+
+/// The session settings for a spectator, over UDP or the matchbox.  Once we
+/// fall `max_frames_behind` frames behind the host, we advance `catchup_speed`
+/// frames per frame until we are back in step.
+pub fn spectator_session_builder(
+    num_players: usize,
+    max_frames_behind: usize,
+    catchup_speed: usize,
+) -> SessionBuilder<ExampleGgrsConfig> {
+    SessionBuilder::<ExampleGgrsConfig>::new()
+        .with_num_players(num_players)
+        .with_fps(FPS)
+        .expect("Invalid FPS")
+        .with_max_frames_behind(max_frames_behind)
+        .expect("Invalid max frames behind")
+        .with_catchup_speed(catchup_speed)
+        .expect("Invalid catchup speed")
+}
+
+/// Follows the match `host` is playing in.  The host must list our address in
+/// its `--udp-spectators` when it starts, as GGRS only sends inputs to the
+/// spectators it knew about from the beginning.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn start_spectator_session(
+    commands: &mut Commands,
+    num_players: usize,
+    port: u16,
+    host: &str,
+    max_frames_behind: usize,
+    catchup_speed: usize,
+) {
+    let host = host.parse::<SocketAddr>().expect("Invalid host address");
+    let udp_socket =
+        UdpNonBlockingSocket::bind_to_port(port).expect("Could not bind to the UDP port");
+    let mut socket = UdpPeerSocket::new(udp_socket);
+    let host = socket.add_peer(host);
+
+    let session = spectator_session_builder(num_players, max_frames_behind, catchup_speed)
+        .start_spectator_session(host, socket);
+
+    info!("Spectating the match hosted by {:?}", host);
+
+    // We do not play, so we have no inputs to give
+    commands.insert_resource(LocalPlayers(Vec::new()));
+    commands.insert_resource(Session::Spectator(session));
+}
+
+pub fn spawn_spectator_hud(mut commands: Commands) {
+    commands.spawn((
+        SpectatorHud,
+        TextBundle::from_section("", TextStyle::default()).with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.),
+            left: Val::Px(10.),
+            ..default()
+        }),
+    ));
+}
+
+pub fn update_spectator_hud(
+    session: Option<Res<Session<ExampleGgrsConfig>>>,
+    mut hud: Query<&mut Text, With<SpectatorHud>>,
+) {
+    let text = match session.as_deref() {
+        Some(Session::Spectator(s)) => {
+            format!("Spectating, {} frames behind host", s.frames_behind_host())
+        }
+        _ => "Waiting for host...".to_string(),
+    };
+
+    for mut hud in hud.iter_mut() {
+        hud.sections[0].value.clone_from(&text);
+    }
+}
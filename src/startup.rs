@@ -1,21 +1,34 @@
 use crate::prelude::*;
 
-pub fn startup(mut commands: Commands) {
+pub fn startup(mut commands: Commands, args: Res<Args>) {
     // frame updating
     commands.insert_resource(CurrentSessionFrame::default());
     commands.insert_resource(RollbackStatus::default());
 
+    // desync reporting
+    commands.insert_resource(RollbackHistory::default());
+    commands.insert_resource(DesyncReported::default());
+    commands.insert_resource(BodyChecksums::default());
+
+    // replay recording
+    commands.insert_resource(ReplayRecording::default());
+
     // physics toggling
     commands.insert_resource(EnablePhysicsAfter::default());
     commands.insert_resource(PhysicsEnabled::default());
 
-    // random movement for testing
+    // random movement for testing, and anything else random
     commands.insert_resource(RandomInput { on: true });
+    commands.insert_resource(RollbackRng::new(args.seed));
+
+    // bodies spawned during the match
+    commands.insert_resource(DynamicSpawns::default());
 }
 
 pub fn reset_rapier(
     mut commands: Commands,
     mut rapier: ResMut<RapierContext>,
+    args: Res<Args>,
     collider_handles: Query<Entity, With<RapierColliderHandle>>,
     rb_handles: Query<Entity, With<RapierRigidBodyHandle>>,
 ) {
@@ -55,20 +68,29 @@ pub fn reset_rapier(
 
     // Serialize our "blank" slate for frame 0.
     // This is actually important because it is possible to rollback to this!
-    if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
-        log::info!("Context hash at init: {:?}", context_bytes.reflect_hash());
+    let mut snapshots = PhysicsSnapshots::new(args.snapshot_format);
+    if let Ok(checksum) = snapshots.save(INITIAL_SNAPSHOT_FRAME, rapier.as_ref()) {
+        log::info!("Context hash at init: {:?}", checksum);
 
         commands.insert_resource(PhysicsRollbackState {
-            rapier_state: context_bytes,
+            frame: INITIAL_SNAPSHOT_FRAME,
+            checksum,
         })
     } else {
         commands.insert_resource(PhysicsRollbackState::default());
     }
+    commands.insert_resource(snapshots);
 }
 
-pub fn respawn_all(mut commands: Commands, spawn_pool: Query<(Entity, &DeterministicSpawn)>) {
+pub fn spawn_camera(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
+}
 
+pub fn respawn_all(
+    mut commands: Commands,
+    spawn_pool: Query<(Entity, &DeterministicSpawn)>,
+    args: Res<Args>,
+) {
     // Everything must be spawned in the same order, every time,
     // deterministically.  There is also potential for bevy itself to return
     // queries to bevy_rapier that do not have the entities in the same order,
@@ -98,35 +120,25 @@ pub fn respawn_all(mut commands: Commands, spawn_pool: Query<(Entity, &Determini
         })
         .add_rollback();
 
-    commands
-        .entity(sorted_entity_pool.pop().unwrap())
-        .insert(Name::new("Player 1"))
-        .insert(Player { handle: 0 })
-        .insert(DynamicColliderBundle {
-            collider: Collider::cuboid(8., 8.),
-            locked_axes: LockedAxes::ROTATION_LOCKED,
-            ..default()
-        })
-        .insert(TransformBundle {
-            local: Transform::from_xyz(-10., -50., 0.),
-            ..default()
-        })
-        .add_rollback();
-
-    commands
-        .entity(sorted_entity_pool.pop().unwrap())
-        .insert(Name::new("Player 2"))
-        .insert(Player { handle: 1 })
-        .insert(DynamicColliderBundle {
-            collider: Collider::cuboid(8., 8.),
-            locked_axes: LockedAxes::ROTATION_LOCKED,
-            ..default()
-        })
-        .insert(TransformBundle {
-            local: Transform::from_xyz(10., -50., 0.),
-            ..default()
-        })
-        .add_rollback();
+    // Line the players up along the bottom, centered, 20 units apart
+    for handle in 0..args.players {
+        let x = (handle as f32 - (args.players - 1) as f32 / 2.) * 20.;
+        commands
+            .entity(sorted_entity_pool.pop().unwrap())
+            .insert(Name::new(format!("Player {}", handle + 1)))
+            .insert(Player { handle })
+            .insert(AbilityCooldowns::default())
+            .insert(DynamicColliderBundle {
+                collider: Collider::cuboid(8., 8.),
+                locked_axes: LockedAxes::ROTATION_LOCKED,
+                ..default()
+            })
+            .insert(TransformBundle {
+                local: Transform::from_xyz(x, -50., 0.),
+                ..default()
+            })
+            .add_rollback();
+    }
 
     let thickness = 10.0;
     let box_length = 200.0;
@@ -248,4 +260,19 @@ pub fn respawn_all(mut commands: Commands, spawn_pool: Query<(Entity, &Determini
             local: Transform::from_xyz(-corner_position, corner_position, 0.),
             ..default()
         });
+
+    // Keep the next few back for spawning during the match.  They are part of
+    // the rollback from the start, even while empty, so rolling back past a
+    // spawn only takes their components away rather than the entities
+    // themselves.  Anything left over from the last match goes, too.
+    let dynamic_pool = (0..DYNAMIC_SLOTS)
+        .map(|_| sorted_entity_pool.pop().unwrap())
+        .collect::<Vec<_>>();
+    for entity in dynamic_pool.iter() {
+        commands
+            .entity(*entity)
+            .remove::<DynamicBodyComponents>()
+            .add_rollback();
+    }
+    commands.insert_resource(DynamicPool(dynamic_pool));
 }
@@ -0,0 +1,128 @@
+use std::net::SocketAddr;
+
+use bevy_ggrs::LocalPlayers;
+use bevy_matchbox::prelude::PeerId;
+use ggrs::{Message, NonBlockingSocket, UdpNonBlockingSocket};
+use uuid::Uuid;
+
+use crate::prelude::*;
+
+/// Written in place of an address in `--udp-players` to mark our own handle
+pub const UDP_LOCAL_PLAYER: &str = "local";
+
+/// GGRS' UDP socket talks in [`SocketAddr`]s, but our GGRS config uses the
+/// matchbox [`PeerId`] as its address.  This gives every remote address a
+/// made-up [`PeerId`] and translates between the two, so the rest of the
+/// example can't tell the difference.
+pub struct UdpPeerSocket {
+    socket: UdpNonBlockingSocket,
+    peers: Vec<(PeerId, SocketAddr)>,
+}
+
+impl UdpPeerSocket {
+    pub fn new(socket: UdpNonBlockingSocket) -> Self {
+        Self {
+            socket,
+            peers: Vec::new(),
+        }
+    }
+
+    /// Remembers `addr`, returning the [`PeerId`] we will know it by
+    pub fn add_peer(&mut self, addr: SocketAddr) -> PeerId {
+        if let Some((peer, _)) = self.peers.iter().find(|(_, a)| *a == addr) {
+            return *peer;
+        }
+
+        // Anything unique works, these never leave this process
+        let peer = PeerId(Uuid::from_u128(self.peers.len() as u128 + 1));
+        self.peers.push((peer, addr));
+        peer
+    }
+}
+
+impl NonBlockingSocket<PeerId> for UdpPeerSocket {
+    fn send_to(&mut self, msg: &Message, addr: &PeerId) {
+        if let Some((_, addr)) = self.peers.iter().find(|(p, _)| p == addr) {
+            self.socket.send_to(msg, addr);
+        }
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        self.socket
+            .receive_all_messages()
+            .into_iter()
+            .filter_map(|(addr, msg)| {
+                // Drop anything from someone we did not ask to play with
+                self.peers
+                    .iter()
+                    .find(|(_, a)| *a == addr)
+                    .map(|(peer, _)| (*peer, msg))
+            })
+            .collect()
+    }
+}
+
+/// Starts a P2P session straight over UDP, with no signaling server at all.
+/// Every player is listed in handle order, with [`UDP_LOCAL_PLAYER`] standing
+/// in for us.  Any `spectators` will be sent our confirmed inputs so they can
+/// watch along.
+pub fn start_udp_session(
+    commands: &mut Commands,
+    num_players: usize,
+    port: u16,
+    players: &[String],
+    spectators: &[String],
+) {
+    assert_eq!(
+        players.len(),
+        num_players,
+        "--udp-players needs exactly {} players",
+        num_players
+    );
+
+    let udp_socket =
+        UdpNonBlockingSocket::bind_to_port(port).expect("Could not bind to the UDP port");
+    let mut socket = UdpPeerSocket::new(udp_socket);
+
+    let mut session_build = session_builder(num_players)
+        .with_desync_detection_mode(ggrs::DesyncDetection::On { interval: 1 });
+    let mut handles = Vec::new();
+    for (i, player) in players.iter().enumerate() {
+        let player = if player == UDP_LOCAL_PLAYER {
+            handles.push(i);
+            PlayerType::Local
+        } else {
+            let addr = player
+                .parse::<SocketAddr>()
+                .expect("Invalid player address");
+            PlayerType::Remote(socket.add_peer(addr))
+        };
+        session_build = session_build
+            .add_player(player, i)
+            .expect("Invalid player added.");
+    }
+
+    // Spectator handles come after all of the players
+    for (i, spectator) in spectators.iter().enumerate() {
+        let addr = spectator
+            .parse::<SocketAddr>()
+            .expect("Invalid spectator address");
+        session_build = session_build
+            .add_player(
+                PlayerType::Spectator(socket.add_peer(addr)),
+                num_players + i,
+            )
+            .expect("Invalid spectator added.");
+    }
+
+    info!(
+        "Starting UDP session on port {} with {:?}, spectated by {:?}",
+        port, players, spectators
+    );
+    let session = session_build
+        .start_p2p_session(socket)
+        .expect("Session could not be created.");
+
+    commands.insert_resource(LocalPlayers(handles));
+    commands.insert_resource(Session::P2P(session));
+}